@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::download::*;
+use crate::disk_maintenance::*;
+
+// Cache layout:
+//   sha256/<hex>        the artifact itself, stored once per content
+//   urls/<hex>.json     UrlEntry for the url whose sha256 is <hex>
+#[derive(Debug, Serialize, Deserialize)]
+struct UrlEntry {
+  url: String,
+  sha256: String,
+}
+
+/// AZURE_VM_CACHE_DIR, else $XDG_CACHE_HOME/azure-vm/artifacts, else ~/.cache/azure-vm/artifacts
+pub fn artifact_cache_dir() -> PathBuf {
+  if let Ok(cache_dir) = std::env::var("AZURE_VM_CACHE_DIR") {
+    return PathBuf::from(cache_dir);
+  }
+  let cache_home = match std::env::var("XDG_CACHE_HOME") {
+    Ok(cache_home) if !cache_home.is_empty() => PathBuf::from(cache_home),
+    _ => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".cache"),
+  };
+  cache_home.join("azure-vm").join("artifacts")
+}
+
+/// Applies AZURE_VM_MIRROR, a list of PREFIX=REPLACEMENT rewrites separated by ; or whitespace, eg
+///   AZURE_VM_MIRROR='https://fedorapeople.org/=http://mirror.lan/fedora/'
+/// A bare base URL without = serves every artifact by file name, eg AZURE_VM_MIRROR=http://127.0.0.1:8000
+pub fn mirror_url(url: &str) -> String {
+  let mirrors = std::env::var("AZURE_VM_MIRROR").unwrap_or_default();
+  for rule in mirrors.split(|c: char| c == ';' || c.is_whitespace()).filter(|r| !r.is_empty()) {
+    match rule.split_once('=') {
+      Some((prefix, replacement)) => {
+        if let Some(rest) = url.strip_prefix(prefix) {
+          return format!("{}{}", replacement, rest);
+        }
+      }
+      None => {
+        let file_name = url.rsplit('/').next().unwrap_or_default();
+        return format!("{}/{}", rule.trim_end_matches('/'), file_name);
+      }
+    }
+  }
+  url.to_string()
+}
+
+/// A cached copy of url's content, found by sha256 when it is known and by url otherwise
+pub async fn cached_artifact(url: &str, sha256: Option<&str>) -> Option<PathBuf> {
+  let cache_dir = artifact_cache_dir();
+  let sha256 = match sha256 {
+    Some(sha256) => sha256.to_lowercase(),
+    None => {
+      let entry_bytes = tokio::fs::read(url_entry_path(&cache_dir, url)).await.ok()?;
+      serde_json::from_slice::<UrlEntry>(&entry_bytes).ok()?.sha256
+    }
+  };
+  let cached = cache_dir.join("sha256").join(sha256);
+  if cached.exists() { Some(cached) } else { None }
+}
+
+/// Stores file in the cache and records it as the content of every url in urls; returns its sha256
+pub async fn add_to_cache(file: &Path, known_sha256: Option<&str>, urls: &[&str]) -> Result<String, Box<dyn std::error::Error>> {
+  let cache_dir = artifact_cache_dir();
+  tokio::fs::create_dir_all(cache_dir.join("sha256")).await?;
+  tokio::fs::create_dir_all(cache_dir.join("urls")).await?;
+
+  let sha256 = match known_sha256 {
+    Some(sha256) => sha256.to_lowercase(),
+    None => sha256_file(file).await?,
+  };
+  let cached = cache_dir.join("sha256").join(&sha256);
+  if !cached.exists() {
+    link_or_copy(file, &cached).await?;
+  }
+  for url in urls.iter() {
+    let entry = UrlEntry { url: url.to_string(), sha256: sha256.clone() };
+    tokio::fs::write(url_entry_path(&cache_dir, url), serde_json::to_string_pretty(&entry)?).await?;
+  }
+  Ok(sha256)
+}
+
+/// Hard links when src and dst share a filesystem, copies otherwise
+pub async fn link_or_copy(src: &Path, dst: &Path) -> Result<(), Box<dyn std::error::Error>> {
+  if tokio::fs::hard_link(src, dst).await.is_err() {
+    let tmp_dst = dst.with_extension("copying");
+    tokio::fs::copy(src, &tmp_dst).await?;
+    tokio::fs::rename(&tmp_dst, dst).await?;
+  }
+  Ok(())
+}
+
+fn url_entry_path(cache_dir: &Path, url: &str) -> PathBuf {
+  let url_key: String = openssl::sha::sha256(url.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+  cache_dir.join("urls").join(format!("{}.json", url_key))
+}
+
+/// artifacts import PATH [URL...] | artifacts list
+pub async fn artifacts_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+  let cache_dir = artifact_cache_dir();
+  match args.first().map(|a| a.as_str()).unwrap_or("list") {
+    "import" => {
+      let path = args.get(1).ok_or("Usage: artifacts import PATH [URL...]")?;
+      let urls: Vec<&str> = args[2..].iter().map(|u| u.as_str()).collect();
+      let sha256 = add_to_cache(Path::new(path), None, &urls).await?;
+      println!("Imported {} as sha256 {}", path, sha256);
+      if urls.is_empty() {
+        println!("No URL given, it will only be used where the config pins this sha256 (eg boot_iso_sha256).");
+      }
+      Ok(())
+    }
+    "list" => {
+      println!("Artifact cache {}:", cache_dir.display());
+      let mut url_entries = match tokio::fs::read_dir(cache_dir.join("urls")).await {
+        Ok(url_entries) => url_entries,
+        Err(_) => return Ok(()),
+      };
+      while let Some(url_entry) = url_entries.next_entry().await? {
+        let entry: UrlEntry = match serde_json::from_slice(&tokio::fs::read(url_entry.path()).await?) {
+          Ok(entry) => entry,
+          Err(_) => continue,
+        };
+        let short_sha256 = entry.sha256.get(..12).unwrap_or(&entry.sha256);
+        let size = tokio::fs::metadata(cache_dir.join("sha256").join(&entry.sha256)).await.map(|m| m.len());
+        match size {
+          Ok(size) => println!("  {} {:>8} {}", short_sha256, human_size(size), entry.url),
+          Err(_) => println!("  {} missing  {}", short_sha256, entry.url),
+        }
+      }
+      Ok(())
+    }
+    unknown => Err(format!("Unknown artifacts action {:?}, expected import or list", unknown).into()),
+  }
+}
@@ -0,0 +1,217 @@
+use std::path::{Path, PathBuf};
+
+use qapi::qmp;
+use serde::{Serialize, Deserialize};
+
+use crate::structs::*;
+use crate::disks::*;
+use crate::disk_maintenance::*;
+use crate::qmp::*;
+use crate::hibernate::*;
+
+// Persistent dirty bitmap on root_disk; it lives inside the qcow2 so it survives VM restarts
+const BACKUP_BITMAP: &str = "azure-vm-backup";
+
+/// manifest.json in the backup directory, one entry per backup point in creation order
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BackupManifest {
+  points: Vec<BackupPoint>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupPoint {
+  name: String,
+  kind: String, // "full" or "incremental"
+  file: String, // Relative to the backup directory; incrementals have their parent as backing file
+  parent: Option<String>,
+  created_unix: u64,
+}
+
+/// backup full|incremental|list|restore POINT NEW.qcow2
+pub async fn backup_command(vm_config: &VMConfig, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+  if vm_config.vm.disk_partuuid.len() > 1 {
+    return Err(format!("Backups need a qcow2 root disk, {} boots the physical disk {}", vm_config.vm.name, vm_config.vm.disk_partuuid).into());
+  }
+
+  let backup_dir = vm_config.vm.backup_dir_path();
+  tokio::fs::create_dir_all(&backup_dir).await?;
+  let manifest_path = backup_dir.join("manifest.json");
+  let mut manifest: BackupManifest = match tokio::fs::read(&manifest_path).await {
+    Ok(manifest_bytes) => serde_json::from_slice(&manifest_bytes)?,
+    Err(_) => BackupManifest::default(),
+  };
+
+  match args.first().copied().unwrap_or("list") {
+    "list" => {
+      println!("Backups of {} in {}:", vm_config.vm.name, backup_dir.display());
+      for point in manifest.points.iter() {
+        println!("  {:<32} {:<12} parent={}", point.name, point.kind, point.parent.as_deref().unwrap_or("-"));
+      }
+      return Ok(());
+    }
+    "full" => {
+      let point = backup_full(vm_config, &backup_dir).await?;
+      manifest.points.push(point);
+    }
+    "incremental" => {
+      let parent = manifest.points.last().cloned().ok_or("No previous backup to build on, run `backup full` first")?;
+      let point = backup_incremental(vm_config, &backup_dir, &parent).await?;
+      manifest.points.push(point);
+    }
+    "restore" => {
+      let (point_name, new_image) = match (args.get(1), args.get(2)) {
+        (Some(point_name), Some(new_image)) => (*point_name, PathBuf::from(new_image)),
+        _ => return Err("Usage: backup restore POINT NEW.qcow2".into()),
+      };
+      let point = manifest.points.iter().find(|p| p.name == point_name).ok_or(format!("No backup point named {}", point_name))?;
+      if new_image.exists() {
+        return Err(format!("Refusing to overwrite {}", new_image.display()).into());
+      }
+      // Converting the top of the chain flattens the full backup and every incremental below it
+      run_qemu_img(&["convert", "-p", "-O", "qcow2", &backup_dir.join(&point.file).to_string_lossy(), &new_image.to_string_lossy()]).await?;
+      // RAM saved against the old disk_image must not be resumed on top of the restored one
+      invalidate_hibernated_state(&vm_config.vm).await;
+      println!("Restored {} to {}, point disk_image at it to boot it.", point.name, new_image.display());
+      return Ok(());
+    }
+    unknown => return Err(format!("Unknown backup action {:?}, expected full, incremental, list or restore", unknown).into()),
+  }
+
+  tokio::fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?).await?;
+  Ok(())
+}
+
+async fn backup_full(vm_config: &VMConfig, backup_dir: &Path) -> Result<BackupPoint, Box<dyn std::error::Error>> {
+  let created_unix = unix_now();
+  let name = format!("full-{}", created_unix);
+  let file = format!("{}.qcow2", name);
+  let target = backup_dir.join(&file);
+
+  match qmp_connect_if_running(&vm_config.vm.flag_path(".qmp.sock")).await? {
+    Some(qmp) => {
+      let root_node = qmp_node_name_of_device(&qmp, "root_disk").await?;
+      let bitmap_exists = root_disk_has_bitmap(&qmp).await?;
+
+      // Starting the bitmap and the full copy in one transaction means no write can slip between them
+      let bitmap_action = if bitmap_exists {
+        qmp::TransactionAction::block_dirty_bitmap_clear(qmp::BlockDirtyBitmapWrapper {
+          data: qmp::BlockDirtyBitmap { node: root_node.clone(), name: BACKUP_BITMAP.to_string() },
+        })
+      }
+      else {
+        qmp::TransactionAction::block_dirty_bitmap_add(qmp::BlockDirtyBitmapAddWrapper {
+          data: qmp::BlockDirtyBitmapAdd { node: root_node.clone(), name: BACKUP_BITMAP.to_string(), persistent: Some(true), disabled: None, granularity: None },
+        })
+      };
+      let job_id = format!("backup-{}", name);
+      println!("Starting full backup of {} to {}", root_node, target.display());
+      if let Err(e) = qmp.execute(qmp::transaction {
+        actions: vec![bitmap_action, drive_backup_action(&job_id, &root_node, &target, qmp::MirrorSyncMode::full, None)],
+        properties: None,
+      }).await {
+        remove_partial_backup(&target).await;
+        return Err(e.into());
+      }
+      if let Err(e) = qmp_wait_for_job(&qmp, &job_id).await {
+        // The transaction already reset the bitmap, so it no longer covers the writes since the last
+        // good backup; dropping it makes `backup incremental` refuse until a full backup succeeds
+        dump_error!( qmp.execute(qmp::block_dirty_bitmap_remove(qmp::BlockDirtyBitmap { node: root_node.clone(), name: BACKUP_BITMAP.to_string() })).await );
+        remove_partial_backup(&target).await;
+        return Err(e);
+      }
+    }
+    None => {
+      // Stopped: copy the image and reset the bitmap offline so the next incremental starts from here
+      let disk_image = vm_config.vm.disk_image.to_string_lossy().to_string();
+      if let Err(e) = run_qemu_img(&["convert", "-p", "-O", "qcow2", &disk_image, &target.to_string_lossy()]).await {
+        remove_partial_backup(&target).await;
+        return Err(e);
+      }
+      if run_qemu_img(&["bitmap", "--clear", &disk_image, BACKUP_BITMAP]).await.is_err() {
+        run_qemu_img(&["bitmap", "--add", &disk_image, BACKUP_BITMAP]).await?;
+      }
+    }
+  }
+
+  println!();
+  println!("Full backup {} written to {}", name, target.display());
+  Ok(BackupPoint { name, kind: "full".into(), file, parent: None, created_unix })
+}
+
+async fn backup_incremental(vm_config: &VMConfig, backup_dir: &Path, parent: &BackupPoint) -> Result<BackupPoint, Box<dyn std::error::Error>> {
+  let qmp = qmp_connect_if_running(&vm_config.vm.flag_path(".qmp.sock")).await?
+    .ok_or("Incremental backups read the live dirty bitmap, start the VM first")?;
+  let root_node = qmp_node_name_of_device(&qmp, "root_disk").await?;
+  if !root_disk_has_bitmap(&qmp).await? {
+    return Err(format!("root_disk has no {} bitmap, run `backup full` first", BACKUP_BITMAP).into());
+  }
+
+  let created_unix = unix_now();
+  let name = format!("incremental-{}", created_unix);
+  let file = format!("{}.qcow2", name);
+  let target = backup_dir.join(&file);
+
+  // Each incremental is an overlay on its parent, so the newest file always reads as the full disk
+  let virtual_size = qemu_img_info(&vm_config.vm.disk_image).await?.first().map(|info| info.virtual_size).unwrap_or(0);
+  run_qemu_img(&[
+    "create", "-f", "qcow2", "-F", "qcow2", "-b", &backup_dir.join(&parent.file).to_string_lossy(),
+    &target.to_string_lossy(), &virtual_size.to_string(),
+  ]).await?;
+
+  let job_id = format!("backup-{}", name);
+  println!("Starting incremental backup of {} to {}", root_node, target.display());
+  // A failed incremental leaves the bitmap as it was (bitmap-mode on-success), only the overlay goes
+  let result = match qmp.execute(qmp::transaction {
+    actions: vec![drive_backup_action(&job_id, &root_node, &target, qmp::MirrorSyncMode::incremental, Some(BACKUP_BITMAP.to_string()))],
+    properties: None,
+  }).await {
+    Ok(_) => qmp_wait_for_job(&qmp, &job_id).await,
+    Err(e) => Err(e.into()),
+  };
+  if let Err(e) = result {
+    remove_partial_backup(&target).await;
+    return Err(e);
+  }
+
+  println!();
+  println!("Incremental backup {} written to {}", name, target.display());
+  Ok(BackupPoint { name, kind: "incremental".into(), file, parent: Some(parent.name.clone()), created_unix })
+}
+
+fn drive_backup_action(job_id: &str, node: &str, target: &Path, sync: qmp::MirrorSyncMode, bitmap: Option<String>) -> qmp::TransactionAction {
+  // Incrementals write into a pre-created overlay, fulls let QEMU create the target
+  let mode = if bitmap.is_some() { qmp::NewImageMode::existing } else { qmp::NewImageMode::absolute_paths };
+  qmp::TransactionAction::drive_backup(qmp::DriveBackupWrapper {
+    data: qmp::DriveBackup {
+      target: target.to_string_lossy().to_string(),
+      format: Some("qcow2".to_string()),
+      mode: Some(mode),
+      base: qmp::BackupCommon {
+        device: node.to_string(),
+        sync,
+        job_id: Some(job_id.to_string()),
+        bitmap,
+        auto_dismiss: Some(false), // Keep failed jobs around long enough to read their error
+        auto_finalize: None, bitmap_mode: None, compress: None, filter_node_name: None,
+        on_source_error: None, on_target_error: None, speed: None, x_perf: None,
+      },
+    },
+  })
+}
+
+async fn remove_partial_backup(target: &Path) {
+  if target.exists() {
+    println!("Removing the incomplete {}", target.display());
+    dump_error!( tokio::fs::remove_file(target).await );
+  }
+}
+
+async fn root_disk_has_bitmap(qmp: &QmpHandle) -> Result<bool, Box<dyn std::error::Error>> {
+  let block_devs = qmp.execute(qmp::query_block { }).await?;
+  Ok(block_devs.iter()
+    .filter(|dev| dev.device == "root_disk")
+    .filter_map(|dev| dev.inserted.as_ref())
+    .filter_map(|inserted| inserted.dirty_bitmaps.as_ref())
+    .flatten()
+    .any(|bitmap| bitmap.name.as_deref() == Some(BACKUP_BITMAP)))
+}
@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+
+use crate::structs::*;
+use crate::disks::*;
+use crate::qmp::*;
+
+/// clone SRC NEW_NAME [--flatten]
+/// Creates a linked clone of SRC's qcow2 disk_image and writes NEW_NAME's .toml next to SRC's.
+pub async fn clone_command(src_config_path: &Path, new_name: &str, flatten: bool) -> Result<(), Box<dyn std::error::Error>> {
+  // Straight from the file, so environment overrides of this shell do not end up in the clone's config
+  let mut vm_config: VMConfig = toml::from_str(&tokio::fs::read_to_string(src_config_path).await?)?;
+  if vm_config.vm.disk_partuuid.len() > 1 {
+    return Err(format!("Cannot clone {}, it boots the physical disk {}", vm_config.vm.name, vm_config.vm.disk_partuuid).into());
+  }
+  if !vm_config.vm.disk_image.exists() {
+    return Err(format!("Cannot clone {}, {} does not exist", vm_config.vm.name, vm_config.vm.disk_image.display()).into());
+  }
+  if qmp_connect_if_running(&vm_config.vm.flag_path(".qmp.sock")).await?.is_some() {
+    return Err(format!("{} is running, shut it down before using it as a golden image", vm_config.vm.name).into());
+  }
+
+  // Both VMs opening the same writable raw file or block device would corrupt it
+  for disk in vm_config.disks.iter() {
+    if (disk.kind == DiskKind::Raw || disk.kind == DiskKind::Physical) && !disk.readonly {
+      return Err(format!("Cannot clone {}, [[disks]] {} is a writable {:?} disk the clone would share; mark it readonly = true or remove it first", vm_config.vm.name, disk.path.display(), disk.kind).into());
+    }
+  }
+
+  let file_stem = new_name.to_lowercase().replace([' ', '/'], "-");
+  let new_config_path = src_config_path.with_file_name(format!("{}.toml", file_stem));
+  let new_disk_image = vm_config.vm.disk_image.with_file_name(format!("{}.qcow2", file_stem));
+  let mut new_data_disks = vec![];
+  for disk in vm_config.disks.iter() {
+    if disk.kind == DiskKind::Qcow2 && !disk.readonly {
+      let disk_file_name = disk.path.file_name().unwrap_or_default().to_string_lossy().to_string();
+      new_data_disks.push(Some(disk.path.with_file_name(format!("{}.{}", file_stem, disk_file_name))));
+    }
+    else {
+      new_data_disks.push(None); // cdroms and readonly disks are never written, so both VMs share them
+    }
+  }
+  for new_file in [&new_config_path, &new_disk_image].into_iter().chain(new_data_disks.iter().flatten()) {
+    if new_file.exists() {
+      return Err(format!("Refusing to overwrite {}", new_file.display()).into());
+    }
+  }
+
+  let backing_file = clone_qcow2(&vm_config.vm.disk_image, &new_disk_image, flatten).await?;
+  let mut data_backing_files = vec![];
+  for (disk, new_path) in vm_config.disks.iter_mut().zip(new_data_disks) {
+    let new_path = match new_path {
+      Some(new_path) => new_path,
+      None => continue,
+    };
+    // Not created yet (size_gb), so the clone simply gets its own empty disk on first boot
+    if disk.path.exists() {
+      data_backing_files.push(clone_qcow2(&disk.path, &new_path, flatten).await?);
+      println!("Wrote {}", new_path.display());
+    }
+    disk.path = new_path;
+  }
+
+  let src_installed_flag = vm_config.vm.flag_path(".installed");
+  let src_name = vm_config.vm.name.clone();
+
+  vm_config.vm.name = new_name.to_string();
+  vm_config.vm.disk_image = new_disk_image.clone();
+  vm_config.vm.addtl_args = copy_firmware_vars(&vm_config.vm.addtl_args, &new_disk_image, &file_stem).await?;
+
+  // The golden image is already installed, so is the clone
+  if src_installed_flag.exists() {
+    tokio::fs::write(vm_config.vm.flag_path(".installed"), b"").await?;
+  }
+
+  let new_config = format!("# Cloned from {} ({}) by azure-vm clone\n\n{}", src_name, src_config_path.display(), toml::to_string_pretty(&vm_config)?);
+  tokio::fs::write(&new_config_path, new_config).await?;
+
+  println!("Wrote {}", new_config_path.display());
+  println!("Wrote {}", new_disk_image.display());
+  if !flatten {
+    for backing_file in [backing_file].iter().chain(data_backing_files.iter()) {
+      println!("{} is now a backing file, booting {} again will corrupt this clone. Use --flatten to detach it.", backing_file.display(), src_name);
+    }
+  }
+  Ok(())
+}
+
+/// Linked qcow2 overlay of src at dst, or a standalone copy with flatten; returns src's absolute path
+async fn clone_qcow2(src: &Path, dst: &Path, flatten: bool) -> Result<PathBuf, Box<dyn std::error::Error>> {
+  // Absolute backing path so the clone keeps working if it is moved
+  let backing_file = tokio::fs::canonicalize(src).await?;
+  run_qemu_img(&["create", "-f", "qcow2", "-F", "qcow2", "-b", &backing_file.to_string_lossy(), &dst.to_string_lossy()]).await?;
+
+  if flatten {
+    let flat_image = dst.with_extension("flat.qcow2");
+    run_qemu_img(&["convert", "-p", "-O", "qcow2", &dst.to_string_lossy(), &flat_image.to_string_lossy()]).await?;
+    tokio::fs::rename(&flat_image, dst).await?;
+  }
+  Ok(backing_file)
+}
+
+/// Writable pflash drives hold per-VM UEFI variables; give the clone its own copy
+async fn copy_firmware_vars(addtl_args: &[String], new_disk_image: &Path, file_stem: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+  let mut new_args = addtl_args.to_vec();
+  for arg in new_args.iter_mut() {
+    if !arg.contains("if=pflash") || arg.contains("readonly=on") {
+      continue;
+    }
+    let vars_file = match arg.split(',').find_map(|opt| opt.strip_prefix("file=")) {
+      Some(vars_file) => PathBuf::from(vars_file),
+      None => continue,
+    };
+    let vars_file_name = vars_file.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let new_vars_file = new_disk_image.with_file_name(format!("{}.{}", file_stem, vars_file_name));
+    if !vars_file.exists() {
+      eprintln!("Not copying firmware vars {}, it does not exist (generated by preboot_cmds?)", vars_file.display());
+      continue;
+    }
+    tokio::fs::copy(&vars_file, &new_vars_file).await?;
+    println!("Copied firmware vars {} to {}", vars_file.display(), new_vars_file.display());
+    *arg = arg.replace(&format!("file={}", vars_file.display()), &format!("file={}", new_vars_file.display()));
+  }
+  Ok(new_args)
+}
@@ -0,0 +1,100 @@
+use std::path::{Path, PathBuf};
+
+use crate::structs::*;
+use crate::secrets::*;
+use crate::iso9660::*;
+
+// Tried in order when [cloud_init] lists no ssh_authorized_keys
+const DEFAULT_PUBLIC_KEYS: [&str; 3] = [".ssh/id_ed25519.pub", ".ssh/id_ecdsa.pub", ".ssh/id_rsa.pub"];
+
+/// Renders [cloud_init] into a NoCloud seed: an ISO labelled cidata holding user-data and meta-data.
+/// The instance-id stays the same across config edits: a new one makes cloud-init treat the VM as a
+/// new machine and regenerate its SSH host keys. Clones get their own, having their own disk_image.
+pub async fn build_cloud_init_seed(vm_config: &VMConfig, cloud_init: &CloudInitBlock) -> Result<PathBuf, Box<dyn std::error::Error>> {
+  let user = if cloud_init.user.is_empty() { "user".to_string() } else { cloud_init.user.clone() };
+  let hostname = cloud_init_hostname(vm_config, cloud_init);
+
+  let mut ssh_authorized_keys = vec![];
+  for key in cloud_init.ssh_authorized_keys.iter() {
+    ssh_authorized_keys.extend(read_authorized_keys(key).await?);
+  }
+  if cloud_init.ssh_authorized_keys.is_empty() {
+    let home = PathBuf::from(std::env::var("HOME").unwrap_or_default());
+    for default_key in DEFAULT_PUBLIC_KEYS.iter() {
+      if let Ok(keys) = read_authorized_keys(&home.join(default_key).to_string_lossy()).await {
+        ssh_authorized_keys.extend(keys);
+      }
+    }
+  }
+  if ssh_authorized_keys.is_empty() && cloud_init.password.is_empty() {
+    eprintln!("[cloud_init] has neither ssh_authorized_keys nor a password, nobody will be able to log in to {}", vm_config.vm.name);
+  }
+
+  let mut user_entry = serde_json::json!({
+    "name": user,
+    "shell": "/bin/bash",
+    "ssh_authorized_keys": ssh_authorized_keys,
+    "lock_passwd": cloud_init.password.is_empty(),
+  });
+  if cloud_init.sudo {
+    user_entry["sudo"] = "ALL=(ALL) NOPASSWD:ALL".into();
+  }
+  if !cloud_init.password.is_empty() {
+    user_entry["plain_text_passwd"] = read_secret(&cloud_init.password).await?.into();
+  }
+
+  let mut cloud_config = serde_json::json!({
+    "hostname": hostname,
+    "users": [user_entry],
+    "ssh_pwauth": !cloud_init.password.is_empty(),
+  });
+  if !cloud_init.packages.is_empty() {
+    cloud_config["package_update"] = true.into();
+    cloud_config["packages"] = cloud_init.packages.clone().into();
+  }
+  if !cloud_init.runcmd.is_empty() {
+    cloud_config["runcmd"] = cloud_init.runcmd.clone().into();
+  }
+
+  // JSON is valid YAML, which saves hand-escaping every value
+  let user_data = format!("#cloud-config\n{}\n", serde_json::to_string_pretty(&cloud_config)?);
+  let instance_id = if cloud_init.instance_id.is_empty() {
+    let disk_image_sha256: String = openssl::sha::sha256(vm_config.vm.disk_image.to_string_lossy().as_bytes()).iter().take(6).map(|b| format!("{:02x}", b)).collect();
+    format!("azure-vm-{}", disk_image_sha256)
+  }
+  else {
+    cloud_init.instance_id.clone()
+  };
+  let meta_data = format!("instance-id: {}\nlocal-hostname: {}\n", instance_id, hostname);
+
+  let iso = build_iso("cidata", &[
+    ("user-data".to_string(), user_data.into_bytes()),
+    ("meta-data".to_string(), meta_data.into_bytes()),
+  ])?;
+  let seed_path = vm_config.vm.flag_path(".cidata.iso");
+  // It may hold the password in plain text
+  write_private_file(&seed_path, &iso).await?;
+  Ok(seed_path)
+}
+
+// An authorized_keys line as-is, or a .pub / authorized_keys file to read them from
+async fn read_authorized_keys(key: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+  if key.starts_with("ssh-") || key.starts_with("ecdsa-") || key.starts_with("sk-") {
+    return Ok(vec![key.to_string()]);
+  }
+  let path = match key.strip_prefix("~/") {
+    Some(rest) => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(rest),
+    None => Path::new(key).to_path_buf(),
+  };
+  let keys = tokio::fs::read_to_string(&path).await.map_err(|e| format!("Cannot read SSH key file {}: {}", path.display(), e))?;
+  Ok(keys.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')).map(|line| line.to_string()).collect())
+}
+
+// Hostnames are letters, digits and dashes
+fn cloud_init_hostname(vm_config: &VMConfig, cloud_init: &CloudInitBlock) -> String {
+  let wanted = if cloud_init.hostname.is_empty() { &vm_config.vm.name } else { &cloud_init.hostname };
+  let hostname: String = wanted.to_lowercase().chars()
+    .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+    .collect::<String>().split('-').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-");
+  if hostname.is_empty() { "vm".to_string() } else { hostname.chars().take(63).collect() }
+}
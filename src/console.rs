@@ -0,0 +1,158 @@
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use nix::sys::termios;
+
+use crate::structs::*;
+
+// Ctrl-], same as telnet and virsh console
+const CONSOLE_DETACH_BYTE: u8 = 0x1d;
+// The log is rotated to .serial.log.1 once it grows past this
+const SERIAL_LOG_ROTATE_BYTES: u64 = 8 * 1024 * 1024;
+const SERIAL_LOG_CHECK_SECS: u64 = 60;
+// drop_to_serial attaches as soon as QEMU has created the socket
+const SERIAL_SOCKET_WAIT_SECS: u64 = 10;
+
+pub fn serial_socket_path(vm: &VMBlock) -> PathBuf {
+  vm.flag_path(".serial.sock")
+}
+
+pub fn serial_log_path(vm: &VMBlock) -> PathBuf {
+  vm.flag_path(".serial.log")
+}
+
+/// -serial or -nographic in addtl_args already route the first serial port, usually to QEMU's stdio
+pub fn serial_in_addtl_args(vm: &VMBlock) -> bool {
+  vm.addtl_args.iter().any(|arg| arg == "-serial" || arg == "-nographic")
+}
+
+/// QEMU only gets the terminal's stdin when addtl_args put something (serial, monitor) on its stdio;
+/// otherwise it would swallow keystrokes meant for the REPL or `console`
+pub fn qemu_stdin(vm: &VMBlock) -> std::process::Stdio {
+  if vm.addtl_args.iter().any(|arg| arg == "-nographic" || arg.contains("stdio")) {
+    std::process::Stdio::inherit()
+  }
+  else {
+    std::process::Stdio::null()
+  }
+}
+
+/// The guest's first serial port, on a socket `console` attaches to and copied into .serial.log
+/// whether or not anyone is attached. Left alone when addtl_args route it themselves.
+pub fn serial_console_args(vm: &VMBlock) -> Vec<String> {
+  if serial_in_addtl_args(vm) {
+    return vec![];
+  }
+  vec![
+    "-chardev".into(), format!("socket,id=serial0,path={},server=on,wait=off,logfile={},logappend=on",
+      serial_socket_path(vm).display(), serial_log_path(vm).display()),
+    "-serial".into(), "chardev:serial0".into(),
+  ]
+}
+
+/// QEMU keeps the log open with O_APPEND (logappend=on), so it is copied aside and truncated in
+/// place rather than renamed; output written between the copy and the truncate is lost.
+pub async fn rotate_serial_log(log: &Path) -> Result<(), Box<dyn std::error::Error>> {
+  if let Ok(metadata) = tokio::fs::metadata(log).await {
+    if metadata.len() > SERIAL_LOG_ROTATE_BYTES {
+      let mut rotated = log.to_path_buf().into_os_string();
+      rotated.push(".1");
+      tokio::fs::copy(log, rotated).await?;
+      tokio::fs::OpenOptions::new().write(true).open(log).await?.set_len(0).await?;
+    }
+  }
+  Ok(())
+}
+
+/// Keeps a chatty guest from growing .serial.log without limit while it runs
+pub async fn serial_log_rotate_task(log: PathBuf) {
+  loop {
+    tokio::time::sleep(tokio::time::Duration::from_secs(SERIAL_LOG_CHECK_SECS)).await;
+    dump_error!( rotate_serial_log(&log).await );
+  }
+}
+
+/// drop_to_serial: attaches the terminal to the serial console right after QEMU starts;
+/// detaching with Ctrl-] leaves the REPL running
+pub async fn attach_console_at_boot(vm: &VMBlock) -> Result<(), Box<dyn std::error::Error>> {
+  let socket_path = serial_socket_path(vm);
+  for _ in 0..(SERIAL_SOCKET_WAIT_SECS * 2) {
+    if socket_path.exists() {
+      break;
+    }
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+  }
+  console_command(vm).await
+}
+
+// Puts the terminal back the way it was however the console session ends
+struct RawModeGuard {
+  fd: i32,
+  original: termios::Termios,
+}
+
+impl RawModeGuard {
+  fn enter(fd: i32) -> Result<RawModeGuard, Box<dyn std::error::Error>> {
+    let original = termios::tcgetattr(fd).map_err(|e| format!("console needs a terminal on stdin: {}", e))?;
+    let mut raw = original.clone();
+    termios::cfmakeraw(&mut raw);
+    termios::tcsetattr(fd, termios::SetArg::TCSANOW, &raw)?;
+    Ok(RawModeGuard { fd, original })
+  }
+}
+
+impl Drop for RawModeGuard {
+  fn drop(&mut self) {
+    dump_error!( termios::tcsetattr(self.fd, termios::SetArg::TCSANOW, &self.original) );
+  }
+}
+
+/// console: attaches the terminal to the guest's serial port in raw mode until Ctrl-] is pressed
+pub async fn console_command(vm: &VMBlock) -> Result<(), Box<dyn std::error::Error>> {
+  let socket_path = serial_socket_path(vm);
+  if serial_in_addtl_args(vm) {
+    return Err("addtl_args route the serial port themselves (-serial / -nographic), there is no console socket".into());
+  }
+  let serial = tokio::net::UnixStream::connect(&socket_path).await
+    .map_err(|e| format!("Cannot connect to {}, is the VM running? {}", socket_path.display(), e))?;
+  let (mut serial_rx, mut serial_tx) = serial.into_split();
+
+  println!("Attached to the serial console, press Ctrl-] to detach. Log: {}", serial_log_path(vm).display());
+  let mut stdin = tokio::io::stdin();
+  let mut stdout = tokio::io::stdout();
+  let raw_mode = RawModeGuard::enter(std::io::stdin().as_raw_fd())?;
+
+  let mut from_terminal = [0u8; 1024];
+  let mut from_guest = [0u8; 4096];
+  loop {
+    tokio::select! {
+      n = stdin.read(&mut from_terminal) => {
+        let n = n?;
+        if n == 0 {
+          break;
+        }
+        match from_terminal[..n].iter().position(|b| *b == CONSOLE_DETACH_BYTE) {
+          Some(detach_at) => {
+            serial_tx.write_all(&from_terminal[..detach_at]).await?;
+            break;
+          }
+          None => serial_tx.write_all(&from_terminal[..n]).await?,
+        }
+      }
+      n = serial_rx.read(&mut from_guest) => {
+        let n = n?;
+        if n == 0 {
+          drop(raw_mode);
+          println!("\nSerial console closed, the VM has likely exited");
+          return Ok(());
+        }
+        stdout.write_all(&from_guest[..n]).await?;
+        stdout.flush().await?;
+      }
+    }
+  }
+  drop(raw_mode);
+  println!("\nDetached from the serial console");
+  Ok(())
+}
@@ -0,0 +1,317 @@
+use std::path::{Path, PathBuf};
+
+use qapi::qmp;
+use serde::Deserialize;
+
+use crate::structs::*;
+use crate::disks::*;
+use crate::qmp::*;
+use crate::hibernate::*;
+
+/// One image in the output of `qemu-img info --output=json --backing-chain`
+#[derive(Debug, Deserialize)]
+pub struct QemuImgInfo {
+  pub filename: String,
+  pub format: String,
+  #[serde(rename = "virtual-size")]
+  pub virtual_size: u64,
+  #[serde(rename = "actual-size", default)]
+  pub actual_size: u64,
+  #[serde(rename = "backing-filename", default)]
+  pub backing_filename: Option<String>,
+  #[serde(rename = "backing-filename-format", default)]
+  pub backing_filename_format: Option<String>,
+  #[serde(default)]
+  pub snapshots: Vec<serde_json::Value>,
+}
+
+/// Output of `qemu-img check --output=json`
+#[derive(Debug, Deserialize)]
+struct QemuImgCheck {
+  #[serde(rename = "check-errors", default)]
+  check_errors: u64,
+  #[serde(default)]
+  leaks: u64,
+  #[serde(default)]
+  corruptions: u64,
+  #[serde(rename = "leaks-fixed", default)]
+  leaks_fixed: u64,
+  #[serde(rename = "corruptions-fixed", default)]
+  corruptions_fixed: u64,
+  #[serde(rename = "allocated-clusters", default)]
+  allocated_clusters: u64,
+  #[serde(rename = "total-clusters", default)]
+  total_clusters: u64,
+  #[serde(rename = "fragmented-clusters", default)]
+  fragmented_clusters: u64,
+}
+
+/// disk resize|compact|convert|check|info [NAME] ...
+/// NAME is root_disk (the default) or the id of a [[disks]] entry. Shrinking needs --shrink.
+pub async fn disk_command(vm_config: &VMConfig, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+  let action = args.first().copied().unwrap_or("info");
+  let name = args.get(1).copied().filter(|a| !a.starts_with("--")).unwrap_or("root_disk");
+  let (path, kind) = find_named_disk(vm_config, name)?;
+
+  let qmp = qmp_connect_if_running(&vm_config.vm.flag_path(".qmp.sock")).await?;
+
+  match action {
+    "info" => disk_info(&path, kind).await,
+    "resize" => {
+      let size = args.get(2).copied().filter(|a| !a.starts_with("--")).ok_or("Usage: disk resize NAME SIZE [--shrink]   (eg 200G or +20G)")?;
+      let shrink = args.contains(&"--shrink");
+      if kind == DiskKind::Physical || kind == DiskKind::Cdrom {
+        return Err(format!("{} is a {:?} disk and cannot be resized", name, kind).into());
+      }
+      match qmp {
+        Some(qmp) => {
+          // Ask the running node, under --ephemeral root_disk is the overlay rather than disk_image
+          let node_name = if name == "root_disk" { qmp_node_name_of_device(&qmp, name).await? } else { name.to_string() };
+          let current_size = qmp.execute(qmp::query_named_block_nodes { flat: Some(true) }).await?.iter()
+            .find(|node| node.node_name.as_deref() == Some(node_name.as_str()))
+            .map(|node| node.image.base.virtual_size as u64)
+            .ok_or_else(|| format!("No block node named {} in the running VM", node_name))?;
+          let new_size = parse_size_bytes(size, current_size)?;
+          if new_size < current_size && !shrink {
+            return Err(format!("Refusing to shrink {} from {} to {}, which destroys data past the new end; pass --shrink once the guest's partitions fit", name, human_size(current_size), human_size(new_size)).into());
+          }
+          println!("Resizing running {} from {} to {}", name, human_size(current_size), human_size(new_size));
+          qmp.execute(qmp::block_resize { device: None, node_name: Some(node_name), size: new_size as i64 }).await?;
+          println!("Done! Grow the partition inside the guest to use the new space.");
+          Ok(())
+        }
+        None => {
+          let path_str = path.to_string_lossy().to_string();
+          let mut resize_args = vec!["resize"];
+          if shrink {
+            resize_args.push("--shrink");
+          }
+          resize_args.extend([path_str.as_str(), size]);
+          invalidate_hibernated_state(&vm_config.vm).await;
+          run_qemu_img(&resize_args).await
+        }
+      }
+    }
+    "compact" | "convert" | "check" if qmp.is_some() => {
+      Err(format!("{} is running, shut it down before running disk {}", vm_config.vm.name, action).into())
+    }
+    "compact" => {
+      invalidate_hibernated_state(&vm_config.vm).await;
+      disk_compact(&path, kind).await
+    }
+    "convert" => {
+      let (format, dest) = match (args.get(2), args.get(3)) {
+        (Some(format), Some(dest)) => (*format, Path::new(*dest)),
+        _ => return Err("Usage: disk convert NAME FORMAT DEST   (eg disk convert root_disk raw tiny11.img)".into()),
+      };
+      disk_convert(&path, kind, format, dest).await
+    }
+    "check" => disk_check(&path, kind, args.contains(&"--repair")).await,
+    unknown => Err(format!("Unknown disk action {:?}, expected resize, compact, convert, check or info", unknown).into()),
+  }
+}
+
+/// root_disk or a [[disks]] id, resolved to a path and kind
+fn find_named_disk(vm_config: &VMConfig, name: &str) -> Result<(PathBuf, DiskKind), Box<dyn std::error::Error>> {
+  if name == "root_disk" {
+    if vm_config.vm.disk_partuuid.len() > 1 {
+      return Ok((PathBuf::from(&vm_config.vm.disk_partuuid), DiskKind::Physical));
+    }
+    return Ok((vm_config.vm.disk_image.clone(), DiskKind::Qcow2));
+  }
+  for (i, disk) in vm_config.disks.iter().enumerate() {
+    if disk_node_name(i, disk) == name {
+      return Ok((disk.path.clone(), disk.kind));
+    }
+  }
+  Err(format!("No disk named {}, expected root_disk or the id of a [[disks]] entry", name).into())
+}
+
+pub async fn qemu_img_info(path: &Path) -> Result<Vec<QemuImgInfo>, Box<dyn std::error::Error>> {
+  // -U so this also works while QEMU holds the image lock
+  let output = tokio::process::Command::new("qemu-img")
+    .args(["info", "-U", "--output=json", "--backing-chain", &path.to_string_lossy()])
+    .output()
+    .await?;
+  if !output.status.success() {
+    return Err(format!("qemu-img info {} failed: {}", path.display(), String::from_utf8_lossy(&output.stderr).trim()).into());
+  }
+  Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+async fn disk_info(path: &Path, kind: DiskKind) -> Result<(), Box<dyn std::error::Error>> {
+  if kind == DiskKind::Physical {
+    println!("{} is a physical disk, qemu-img only sees its raw size:", path.display());
+  }
+  for (depth, info) in qemu_img_info(path).await?.iter().enumerate() {
+    let indent = "  ".repeat(depth);
+    println!("{}{} ({})", indent, info.filename, info.format);
+    println!("{}  virtual size: {}", indent, human_size(info.virtual_size));
+    println!("{}  actual size:  {}", indent, human_size(info.actual_size));
+    if !info.snapshots.is_empty() {
+      println!("{}  snapshots:    {}", indent, info.snapshots.len());
+    }
+    if let Some(backing_filename) = &info.backing_filename {
+      println!("{}  backing file: {}", indent, backing_filename);
+    }
+  }
+  Ok(())
+}
+
+async fn disk_compact(path: &Path, kind: DiskKind) -> Result<(), Box<dyn std::error::Error>> {
+  if kind != DiskKind::Qcow2 {
+    return Err(format!("Only qcow2 images can be compacted, {} is {:?}", path.display(), kind).into());
+  }
+  let chain = qemu_img_info(path).await?;
+  let info = chain.first().ok_or("qemu-img info returned nothing")?;
+  if !info.snapshots.is_empty() {
+    return Err(format!("{} has {} snapshots which qemu-img convert would drop, delete them first", path.display(), info.snapshots.len()).into());
+  }
+
+  println!("Tip: zero free space inside the guest first (sdelete -z C: on Windows, fstrim on Linux) so more clusters can be dropped.");
+
+  // Zero clusters are never written to the output, -c compresses whatever is left
+  let compacted = path.with_extension("compact.qcow2");
+  let path_str = path.to_string_lossy().to_string();
+  let compacted_str = compacted.to_string_lossy().to_string();
+  let mut convert_args = vec!["convert", "-p", "-c", "-O", "qcow2"];
+  let backing_format = info.backing_filename_format.clone().unwrap_or("qcow2".to_string());
+  if let Some(backing_filename) = &info.backing_filename {
+    // Keep linked clones linked instead of flattening them
+    convert_args.extend(["-B", backing_filename.as_str(), "-F", backing_format.as_str()]);
+  }
+  convert_args.extend([path_str.as_str(), compacted_str.as_str()]);
+  if let Err(e) = run_qemu_img(&convert_args).await {
+    dump_error!( tokio::fs::remove_file(&compacted).await );
+    return Err(e);
+  }
+
+  let old_size = info.actual_size;
+  tokio::fs::rename(&compacted, path).await?;
+  let new_size = qemu_img_info(path).await?.first().map(|info| info.actual_size).unwrap_or(0);
+  println!("Compacted {} from {} to {}", path.display(), human_size(old_size), human_size(new_size));
+  Ok(())
+}
+
+/// Writes a copy of the disk in another format, eg raw for dd or vmdk / vhdx for other hypervisors.
+/// The VM keeps using the original; point disk_image or the [[disks]] path at DEST to switch.
+async fn disk_convert(path: &Path, kind: DiskKind, format: &str, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+  if !["qcow2", "raw", "vmdk", "vdi", "vhdx", "vpc"].contains(&format) {
+    return Err(format!("Unknown format {:?}, expected qcow2, raw, vmdk, vdi, vhdx or vpc", format).into());
+  }
+  if kind == DiskKind::Cdrom {
+    return Err(format!("{} is a cdrom, there is nothing to convert", path.display()).into());
+  }
+  if dest.exists() {
+    return Err(format!("Refusing to overwrite {}", dest.display()).into());
+  }
+  let source_format = if kind == DiskKind::Qcow2 { "qcow2" } else { "raw" };
+  let path_str = path.to_string_lossy().to_string();
+  let dest_str = dest.to_string_lossy().to_string();
+  // Backing files are read through, so DEST is always standalone
+  if let Err(e) = run_qemu_img(&["convert", "-p", "-f", source_format, "-O", format, &path_str, &dest_str]).await {
+    if dest.exists() {
+      dump_error!( tokio::fs::remove_file(dest).await );
+    }
+    return Err(e);
+  }
+  println!("Wrote {}", dest.display());
+  Ok(())
+}
+
+async fn disk_check(path: &Path, kind: DiskKind, repair: bool) -> Result<(), Box<dyn std::error::Error>> {
+  if kind != DiskKind::Qcow2 {
+    return Err(format!("Only qcow2 images have metadata to check, {} is {:?}", path.display(), kind).into());
+  }
+  let path_str = path.to_string_lossy().to_string();
+  let mut check_args = vec!["check", "--output=json"];
+  if repair {
+    check_args.extend(["-r", "leaks"]);
+  }
+  check_args.push(path_str.as_str());
+
+  // Leaks and corruptions give non-zero exit codes but still print the report, so parse before judging
+  let output = tokio::process::Command::new("qemu-img")
+    .args(&check_args)
+    .output()
+    .await?;
+  let check: QemuImgCheck = serde_json::from_slice(&output.stdout)
+    .map_err(|e| format!("qemu-img check {} failed: {} ({})", path.display(), String::from_utf8_lossy(&output.stderr).trim(), e))?;
+
+  println!("{}:", path.display());
+  println!("  allocated clusters:  {} / {} ({} fragmented)", check.allocated_clusters, check.total_clusters, check.fragmented_clusters);
+  println!("  leaked clusters:     {}{}", check.leaks, if check.leaks_fixed > 0 { format!(" ({} fixed)", check.leaks_fixed) } else { String::new() });
+  println!("  corruptions:         {}{}", check.corruptions, if check.corruptions_fixed > 0 { format!(" ({} fixed)", check.corruptions_fixed) } else { String::new() });
+  println!("  check errors:        {}", check.check_errors);
+
+  if check.corruptions > check.corruptions_fixed || check.check_errors > 0 {
+    return Err(format!("{} is corrupt, restore it from a snapshot or backup", path.display()).into());
+  }
+  if check.leaks > check.leaks_fixed {
+    println!("Leaked clusters only waste space, run `disk check NAME --repair` to reclaim them.");
+  }
+  Ok(())
+}
+
+/// Parses sizes like 200G, 512M or +20G (relative to current_size) into bytes
+fn parse_size_bytes(size: &str, current_size: u64) -> Result<u64, Box<dyn std::error::Error>> {
+  let (relative, size) = match size.strip_prefix('+') {
+    Some(rest) => (true, rest),
+    None => (false, size),
+  };
+  let size = size.trim_end_matches(['B', 'b']).trim_end_matches('i');
+  let digits_end = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+  let number: u64 = size[..digits_end].parse().map_err(|_| format!("Bad size {:?}", size))?;
+  let multiplier: u64 = match size[digits_end..].to_ascii_uppercase().as_str() {
+    "" => 1,
+    "K" => 1024,
+    "M" => 1024 * 1024,
+    "G" => 1024 * 1024 * 1024,
+    "T" => 1024 * 1024 * 1024 * 1024,
+    unit => return Err(format!("Unknown size unit {:?}", unit).into()),
+  };
+  let bytes = number.checked_mul(multiplier).ok_or_else(|| format!("Size {:?} is too large", size))?;
+  if relative {
+    return Ok(current_size.checked_add(bytes).ok_or_else(|| format!("Size +{:?} is too large", size))?);
+  }
+  Ok(bytes)
+}
+
+pub fn human_size(bytes: u64) -> String {
+  let units = ["B", "K", "M", "G", "T"];
+  let mut value = bytes as f64;
+  let mut unit = 0;
+  while value >= 1024.0 && unit < units.len() - 1 {
+    value /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1}{}", value, units[unit])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const GIB: u64 = 1024 * 1024 * 1024;
+
+  #[test]
+  fn parse_size_bytes_absolute_and_relative() {
+    assert_eq!(parse_size_bytes("200G", 10 * GIB).unwrap(), 200 * GIB);
+    assert_eq!(parse_size_bytes("+20G", 10 * GIB).unwrap(), 30 * GIB);
+    assert_eq!(parse_size_bytes("512MiB", 0).unwrap(), 512 * 1024 * 1024);
+    assert_eq!(parse_size_bytes("4096", 0).unwrap(), 4096);
+  }
+
+  #[test]
+  fn parse_size_bytes_overflow() {
+    assert!(parse_size_bytes("99999999999T", 0).is_err());
+    assert!(parse_size_bytes("+1", u64::MAX).is_err());
+  }
+
+  #[test]
+  fn parse_size_bytes_bad_input() {
+    assert!(parse_size_bytes("20X", 0).is_err());
+    assert!(parse_size_bytes("G", 0).is_err());
+    assert!(parse_size_bytes("", 0).is_err());
+  }
+}
@@ -0,0 +1,193 @@
+use std::path::Path;
+
+use crate::structs::*;
+
+// IDE indexes of the root -drive and the CD-ROMs azure-vm attaches itself
+pub const ROOT_DISK_IDE_INDEX: usize = 0;
+pub const INSTALL_ISO_IDE_INDEX: usize = 1;
+pub const VIRTIO_WIN_ISO_IDE_INDEX: usize = 2;
+pub const SEED_ISO_IDE_INDEX: usize = 3; // autounattend.xml media while installing, cidata afterwards
+pub const VIRTIO_WIN_ISO_DRIVE_ID: &str = "virtio_win_media";
+pub const CLOUD_INIT_SEED_DRIVE_ID: &str = "cidata_media";
+
+/// Turns the [[disks]] list into -blockdev / -device pairs.
+/// Controllers for virtio-scsi and ahci are added once, before the first disk that needs them.
+/// bus = "ide" disks take the IDE indexes not in ide_in_use, the ones this launch attaches itself.
+pub fn disks_to_qemu_args(disks: &[VMDisk], machine: &str, ide_in_use: &[usize]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+  let mut args: Vec<String> = vec![];
+  let mut have_scsi_controller = false;
+  let mut have_ahci_controller = false;
+  let mut next_ahci_port = 0;
+  let mut next_ide_index = 0;
+
+  for (i, disk) in disks.iter().enumerate() {
+    let id = disk_node_name(i, disk);
+    let read_only = disk.readonly || disk.kind == DiskKind::Cdrom;
+
+    if disk.kind == DiskKind::Cdrom && (disk.bus == DiskBus::VirtioBlk || disk.bus == DiskBus::Nvme) {
+      return Err(format!("disk {} is a cdrom, which cannot sit on bus {:?}", id, disk.bus).into());
+    }
+
+    // Protocol node, the thing that actually touches the host file or device
+    let file_driver = if disk.kind == DiskKind::Physical { "host_device" } else { "file" };
+    let mut file_node = format!(
+      "driver={},node-name={}-file,filename={},{}",
+      file_driver, id, disk.path.display(), blockdev_cache_opts(disk.cache)
+    );
+    if disk.kind == DiskKind::Physical {
+      warn_if_unsafe_physical(disk.cache, &disk.path);
+      file_node.push_str(&format!(",aio={}", physical_disk_aio(disk.cache)));
+    }
+    if read_only {
+      file_node.push_str(",read-only=on");
+    }
+    if disk.discard {
+      file_node.push_str(",discard=unmap");
+    }
+
+    // Format node, which is what the guest-facing device is attached to
+    let format_driver = if disk.kind == DiskKind::Qcow2 { "qcow2" } else { "raw" };
+    let mut format_node = format!("driver={},node-name={},file={}-file", format_driver, id, id);
+    if read_only {
+      format_node.push_str(",read-only=on");
+    }
+    if disk.discard {
+      format_node.push_str(",discard=unmap");
+    }
+
+    args.extend(["-blockdev".to_string(), file_node]);
+    args.extend(["-blockdev".to_string(), format_node]);
+
+    let mut device = match disk.bus {
+      DiskBus::VirtioBlk => format!("virtio-blk-pci,drive={}", id),
+      DiskBus::VirtioScsi => {
+        if !have_scsi_controller {
+          args.extend(["-device".to_string(), "virtio-scsi-pci,id=scsi0".to_string()]);
+          have_scsi_controller = true;
+        }
+        let scsi_dev = if disk.kind == DiskKind::Cdrom { "scsi-cd" } else { "scsi-hd" };
+        format!("{},drive={},bus=scsi0.0", scsi_dev, id)
+      }
+      DiskBus::Nvme => {
+        // nvme refuses to start without a serial
+        let serial = if disk.serial.is_empty() { id.clone() } else { disk.serial.clone() };
+        format!("nvme,drive={},serial={}", id, serial)
+      }
+      DiskBus::Ahci => {
+        if !have_ahci_controller {
+          args.extend(["-device".to_string(), "ahci,id=ahci0".to_string()]);
+          have_ahci_controller = true;
+        }
+        let ide_dev = if disk.kind == DiskKind::Cdrom { "ide-cd" } else { "ide-hd" };
+        let dev = format!("{},drive={},bus=ahci0.{}", ide_dev, id, next_ahci_port);
+        next_ahci_port += 1;
+        dev
+      }
+      DiskBus::Ide => {
+        while ide_in_use.contains(&next_ide_index) {
+          next_ide_index += 1;
+        }
+        let (bus, unit) = ide_slot(machine, next_ide_index)
+          .ok_or_else(|| format!("disk {} cannot use bus = \"ide\": {} has no free IDE slot left next to IDE indexes {:?}, use ahci", id, machine, ide_in_use))?;
+        next_ide_index += 1;
+        let ide_dev = if disk.kind == DiskKind::Cdrom { "ide-cd" } else { "ide-hd" };
+        format!("{},drive={},bus=ide.{},unit={}", ide_dev, id, bus, unit)
+      }
+    };
+    device.push_str(&format!(",id={}-dev", id));
+    if !disk.serial.is_empty() && disk.bus != DiskBus::Nvme {
+      device.push_str(&format!(",serial={}", disk.serial));
+    }
+    if let Some(boot_index) = disk.boot_index {
+      device.push_str(&format!(",bootindex={}", boot_index));
+    }
+    if disk.cache == DiskCache::Writethrough || disk.cache == DiskCache::Directsync {
+      device.push_str(",write-cache=off");
+    }
+
+    args.extend(["-device".to_string(), device]);
+  }
+
+  Ok(args)
+}
+
+/// Maps an -drive if=ide index to its (bus, unit): q35's AHCI exposes 6 single-unit buses,
+/// the pc machine's PIIX has 2 buses with a master and a slave each
+pub fn ide_slot(machine: &str, index: usize) -> Option<(usize, usize)> {
+  if machine.contains("q35") {
+    if index < 6 { Some((index, 0)) } else { None }
+  }
+  else if index < 4 { Some((index / 2, index % 2)) } else { None }
+}
+
+/// A read-only CD-ROM at IDE index, as an explicit -device so QMP can address it by id
+pub fn ide_cdrom_args(machine: &str, index: usize, file: &Path, id: &str) -> Vec<String> {
+  let (bus, unit) = ide_slot(machine, index).unwrap_or((index / 2, index % 2));
+  vec![
+    "-drive".into(), format!("file={},if=none,media=cdrom,readonly=on,id={}-drive", file.display(), id),
+    "-device".into(), format!("ide-cd,drive={}-drive,id={},bus=ide.{},unit={}", id, id, bus, unit),
+  ]
+}
+
+/// Node name of a [[disks]] entry, which is also its -blockdev node-name
+pub fn disk_node_name(index: usize, disk: &VMDisk) -> String {
+  if disk.id.is_empty() { format!("disk{}", index) } else { disk.id.clone() }
+}
+
+/// -blockdev has no cache= shorthand, so spell the -drive cache modes out
+fn blockdev_cache_opts(cache: DiskCache) -> &'static str {
+  match cache {
+    DiskCache::None | DiskCache::Directsync => "cache.direct=on,cache.no-flush=off",
+    DiskCache::Writeback | DiskCache::Writethrough => "cache.direct=off,cache.no-flush=off",
+    DiskCache::Unsafe => "cache.direct=off,cache.no-flush=on",
+  }
+}
+
+/// Physical disks get real async I/O; native needs O_DIRECT, io_uring takes anything
+pub fn physical_disk_aio(cache: DiskCache) -> &'static str {
+  if cache.is_direct() { "native" } else { "io_uring" }
+}
+
+pub fn warn_if_unsafe_physical(cache: DiskCache, path: &Path) {
+  if cache == DiskCache::Unsafe {
+    eprintln!();
+    eprintln!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+    eprintln!("!!! WARNING: cache=unsafe on physical disk {}", path.display());
+    eprintln!("!!! Guest flushes are IGNORED; a host crash or power loss WILL corrupt this disk.");
+    eprintln!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+    eprintln!();
+  }
+}
+
+/// Creates any qcow2 [[disks]] that do not exist yet and specify a size_gb
+pub async fn ensure_disks_exist(disks: &[VMDisk]) {
+  for disk in disks.iter() {
+    if disk.kind != DiskKind::Qcow2 || disk.size_gb < 1 || disk.path.exists() {
+      continue;
+    }
+    if let Some(disk_dir) = disk.path.parent() {
+      println!("Ensuring {} exists...", disk_dir.display());
+      dump_error!( tokio::fs::create_dir_all(disk_dir).await );
+    }
+    dump_error!( create_qcow2_image(&disk.path, disk.size_gb).await );
+  }
+}
+
+pub async fn create_qcow2_image(path: &Path, size_gb: usize) -> std::io::Result<std::process::ExitStatus> {
+  tokio::process::Command::new("qemu-img")
+    .args(["create", "-f", "qcow2", &path.to_string_lossy(), format!("{}G", size_gb).as_str() ])
+    .status()
+    .await
+}
+
+pub async fn run_qemu_img(args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+  println!(">>> qemu-img {}", args.join(" "));
+  let status = tokio::process::Command::new("qemu-img")
+    .args(args)
+    .status()
+    .await?;
+  if !status.success() {
+    return Err(format!("qemu-img {} failed with {}", args.join(" "), status).into());
+  }
+  Ok(())
+}
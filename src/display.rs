@@ -0,0 +1,133 @@
+use std::path::Path;
+
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::qmp::*;
+use crate::secrets::*;
+
+// Defaults when display_port is 0
+const SPICE_TCP_DEFAULT_PORT: u16 = 5930;
+const VNC_DEFAULT_PORT: u16 = 5900;
+
+// QEMU opens its QMP socket a moment after starting, slower under systemd-run or a big -m
+const VNC_PASSWORD_TIMEOUT_SECS: u64 = 30;
+
+/// -display / -spice / -vnc / -vga args for the configured display mode.
+/// With -spice or -vnc on the command line QEMU opens no local window of its own.
+pub fn display_qemu_args(vm: &VMBlock, spice_socket: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+  let mut args: Vec<String> = vec![];
+  match vm.display {
+    DisplayMode::Gtk => args.extend(["-display".into(), "gtk".into()]),
+    DisplayMode::Sdl => args.extend(["-display".into(), "sdl".into()]),
+    DisplayMode::SpiceUnix => {
+      args.push("-spice".into()); // /dev/dri/by-path/pci-0000:00:02.0-render is the intel GPU
+      args.push(format!("unix=on,addr={},gl={},disable-ticketing=on", spice_socket.display(), vm.spice_gl_override));
+      //args.push(format!("unix=on,addr={},gl={},rendernode={},disable-ticketing=on", spice_socket.display(), vm.spice_gl_override, vm.spice_rendernode_override ));
+      args.extend(spice_vdagent_args());
+    }
+    DisplayMode::SpiceTcp => {
+      // gl=on only works over the unix socket, a TCP client may be on another machine
+      args.push("-spice".into());
+      args.push(format!("port={},addr=127.0.0.1,disable-ticketing=on", display_port(vm)));
+      args.extend(spice_vdagent_args());
+    }
+    DisplayMode::Vnc => {
+      // VNC display numbers count from port 5900
+      let port = display_port(vm);
+      if port < VNC_DEFAULT_PORT {
+        return Err(format!("display_port = {} cannot be used for vnc, QEMU only listens on {} + display number", port, VNC_DEFAULT_PORT).into());
+      }
+      let mut vnc_arg = format!("127.0.0.1:{}", port - VNC_DEFAULT_PORT);
+      if !vm.vnc_password.is_empty() {
+        vnc_arg.push_str(",password=on"); // Refuses every client until set_vnc_password() runs
+      }
+      if vm.vnc_websocket_port > 0 {
+        vnc_arg.push_str(&format!(",websocket={}", vm.vnc_websocket_port));
+      }
+      args.extend(["-vnc".into(), vnc_arg]);
+    }
+    DisplayMode::None => args.extend(["-display".into(), "none".into()]),
+  }
+
+  // Left to QEMU's default (std) unless asked for; changing it under an installed Windows guest
+  // drops it to the basic display driver. qxl lets the spice guest tools resize the desktop.
+  if !vm.vga_override.is_empty() {
+    args.extend(["-vga".into(), vm.vga_override.clone()]);
+  }
+  Ok(args)
+}
+
+// Clipboard sharing and resizing through the spice guest agent; needs the virtio-serial-pci bus
+fn spice_vdagent_args() -> Vec<String> {
+  vec![
+    "-device".into(), "virtserialport,chardev=spicechannel0,name=com.redhat.spice.0".into(),
+    "-chardev".into(), "spicevmc,id=spicechannel0,name=vdagent".into(),
+  ]
+}
+
+/// Port the spice-tcp / vnc server listens on
+pub fn display_port(vm: &VMBlock) -> u16 {
+  match (vm.display_port, vm.display) {
+    (0, DisplayMode::Vnc) => VNC_DEFAULT_PORT,
+    (0, _) => SPICE_TCP_DEFAULT_PORT,
+    (port, _) => port,
+  }
+}
+
+/// VNC passwords can only be set over QMP, so this retries until QEMU answers there.
+/// Until it succeeds password=on makes VNC refuse every client.
+pub async fn set_vnc_password(vm: &VMBlock, qmp_socket: &Path) -> Result<(), Box<dyn std::error::Error>> {
+  if vm.display != DisplayMode::Vnc || vm.vnc_password.is_empty() {
+    return Ok(());
+  }
+  let password = read_secret(&vm.vnc_password).await?;
+  if password.len() > 8 {
+    eprintln!("VNC only uses the first 8 characters of vnc_password");
+  }
+  let started = std::time::Instant::now();
+  loop {
+    let attempt = match qmp_connect_if_running(qmp_socket).await {
+      Ok(Some(qmp)) => qmp.execute(qmp::change_vnc_password { password: password.clone() }).await.map_err(|e| e.to_string()),
+      Ok(None) => Err("QMP socket is not open yet".to_string()),
+      Err(e) => Err(e.to_string()),
+    };
+    match attempt {
+      Ok(_) => return Ok(()),
+      Err(e) if started.elapsed().as_secs() >= VNC_PASSWORD_TIMEOUT_SECS => {
+        return Err(format!("Could not set the VNC password within {}s, VNC will refuse every client: {}", VNC_PASSWORD_TIMEOUT_SECS, e).into());
+      }
+      Err(_) => tokio::time::sleep(tokio::time::Duration::from_millis(500)).await,
+    }
+  }
+}
+
+/// gui: opens the viewer matching the display mode, whichever of the candidates is installed
+pub async fn gui_command(vm: &VMBlock, spice_socket: &Path) -> Result<(), Box<dyn std::error::Error>> {
+  let port = display_port(vm);
+  let candidates: Vec<(&str, Vec<String>)> = match vm.display {
+    DisplayMode::SpiceUnix => vec![
+      ("spicy", vec![format!("--uri=spice+unix://{}", spice_socket.display())]),
+      ("remote-viewer", vec![format!("spice+unix://{}", spice_socket.display())]),
+    ],
+    DisplayMode::SpiceTcp => vec![
+      ("spicy", vec![format!("--uri=spice://127.0.0.1:{}", port)]),
+      ("remote-viewer", vec![format!("spice://127.0.0.1:{}", port)]),
+    ],
+    DisplayMode::Vnc => vec![
+      ("vncviewer", vec![format!("127.0.0.1::{}", port)]),
+      ("remote-viewer", vec![format!("vnc://127.0.0.1:{}", port)]),
+    ],
+    DisplayMode::Gtk | DisplayMode::Sdl => return Err(format!("display = {:?} already shows the VM in its own window", vm.display).into()),
+    DisplayMode::None => return Err("display = none has no GUI, try rdp or ssh".into()),
+  };
+
+  for (client, args) in candidates.iter() {
+    match tokio::process::Command::new(client).args(args).status().await {
+      Ok(_) => return Ok(()),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+      Err(e) => return Err(e.into()),
+    }
+  }
+  Err(format!("None of {} is installed", candidates.iter().map(|(client, _)| *client).collect::<Vec<_>>().join(", ")).into())
+}
@@ -0,0 +1,160 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::disk_maintenance::*;
+use crate::artifacts::*;
+
+/// Downloads url to local_file through local_file.part, resuming an interrupted download with an HTTP
+/// Range request. local_file only appears once the download is complete and, if sha256 is given, verified.
+/// An existing local_file is kept, after a one-time check against sha256 recorded in local_file.sha256.
+/// Artifacts already in the cache are copied from there, new downloads are added to it.
+pub async fn download_file(url: &str, local_file: &Path, sha256: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+  if local_file.exists() {
+    return match sha256 {
+      Some(sha256) => verify_existing_file(local_file, sha256).await,
+      None => Ok(()),
+    };
+  }
+
+  if let Some(local_parent_dir) = local_file.parent() {
+    tokio::fs::create_dir_all(local_parent_dir).await?;
+  }
+  if let Some(cached) = cached_artifact(url, sha256).await {
+    println!("Using cached {} for {}", cached.display(), url);
+    link_or_copy(&cached, local_file).await?;
+    return match sha256 {
+      Some(sha256) => verify_existing_file(local_file, sha256).await,
+      None => Ok(()),
+    };
+  }
+
+  // Fetch from the mirror, but cache under the original url so configs stay portable
+  let fetch_url = mirror_url(url);
+  let part_file = sibling_with_suffix(local_file, ".part");
+  let client = reqwest::Client::new();
+  let mut started_over = false;
+
+  let (response, mut part, resume_from) = loop {
+    let resume_from = tokio::fs::metadata(&part_file).await.map(|m| m.len()).unwrap_or(0);
+    let mut request = client.get(&fetch_url);
+    if resume_from > 0 {
+      request = request.header(reqwest::header::RANGE, format!("bytes={}-", resume_from));
+    }
+    let response = request.send().await?;
+
+    match response.status() {
+      reqwest::StatusCode::PARTIAL_CONTENT => {
+        println!("Resuming {} at {}", fetch_url, human_size(resume_from));
+        let part = tokio::fs::OpenOptions::new().append(true).open(&part_file).await?;
+        break (response, part, resume_from);
+      }
+      reqwest::StatusCode::RANGE_NOT_SATISFIABLE if !started_over => {
+        // The .part is already as long as the file (or longer), start over once to be safe
+        tokio::fs::remove_file(&part_file).await?;
+        started_over = true;
+      }
+      reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+        return Err(format!("GET {} keeps answering 416 Range Not Satisfiable", fetch_url).into());
+      }
+      status if status.is_success() => {
+        println!("Downloading {} to {}", fetch_url, local_file.display());
+        // A server that ignores the Range header sends everything again, so start the .part over
+        let part = tokio::fs::File::create(&part_file).await?;
+        break (response, part, 0);
+      }
+      status => return Err(format!("GET {} returned {}", fetch_url, status).into()),
+    }
+  };
+
+  let total = response.content_length().map(|len| len + resume_from);
+  let mut done = resume_from;
+  let mut last_progress = std::time::Instant::now();
+  let started = std::time::Instant::now();
+  let mut stream = response.bytes_stream();
+  // Errors leave the .part in place so the next run resumes instead of starting over
+  while let Some(chunk) = stream.next().await {
+    let chunk = chunk?;
+    part.write_all(&chunk).await?;
+    done += chunk.len() as u64;
+    if last_progress.elapsed().as_millis() >= 250 {
+      print_progress(done, total, done - resume_from, started.elapsed().as_secs_f64());
+      last_progress = std::time::Instant::now();
+    }
+  }
+  part.flush().await?;
+  print_progress(done, total, done - resume_from, started.elapsed().as_secs_f64());
+  println!();
+
+  if let Some(total) = total {
+    if done != total {
+      return Err(format!("Download of {} stopped at {} of {}, run again to resume", url, human_size(done), human_size(total)).into());
+    }
+  }
+  if let Some(sha256) = sha256 {
+    let actual = sha256_file(&part_file).await?;
+    if !actual.eq_ignore_ascii_case(sha256) {
+      dump_error!( tokio::fs::remove_file(&part_file).await );
+      return Err(format!("{} has sha256 {} but {} was expected, deleted the download", url, actual, sha256).into());
+    }
+    tokio::fs::write(sibling_with_suffix(local_file, ".sha256"), &actual).await?;
+  }
+  tokio::fs::rename(&part_file, local_file).await?;
+
+  if let Err(e) = add_to_cache(local_file, sha256, &[url]).await {
+    eprintln!("Could not add {} to the artifact cache: {}", local_file.display(), e);
+  }
+  Ok(())
+}
+
+pub async fn sha256_file(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+  let mut file = tokio::fs::File::open(path).await?;
+  let mut hasher = openssl::sha::Sha256::new();
+  let mut buf = vec![0u8; 1024 * 1024];
+  loop {
+    let n = file.read(&mut buf).await?;
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buf[..n]);
+  }
+  Ok(hasher.finish().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+async fn verify_existing_file(local_file: &Path, sha256: &str) -> Result<(), Box<dyn std::error::Error>> {
+  let checked_marker = sibling_with_suffix(local_file, ".sha256");
+  if let Ok(checked) = tokio::fs::read_to_string(&checked_marker).await {
+    if checked.trim().eq_ignore_ascii_case(sha256) {
+      return Ok(());
+    }
+  }
+  println!("Verifying {}...", local_file.display());
+  let actual = sha256_file(local_file).await?;
+  if !actual.eq_ignore_ascii_case(sha256) {
+    return Err(format!("{} has sha256 {} but {} was expected; delete it to download it again", local_file.display(), actual, sha256).into());
+  }
+  tokio::fs::write(&checked_marker, &actual).await?;
+  Ok(())
+}
+
+fn sibling_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+  file_name.push(suffix);
+  path.with_file_name(file_name)
+}
+
+fn print_progress(done: u64, total: Option<u64>, transferred: u64, elapsed_secs: f64) {
+  let rate = if elapsed_secs > 0.0 { (transferred as f64 / elapsed_secs) as u64 } else { 0 };
+  match total {
+    Some(total) if total > 0 => {
+      let width = 30;
+      let filled = (done * width / total).min(width) as usize;
+      print!("\r[{}{}] {:>3}% {} / {} {}/s   ", "#".repeat(filled), " ".repeat(width as usize - filled),
+        done * 100 / total, human_size(done), human_size(total), human_size(rate));
+    }
+    _ => print!("\r{} {}/s   ", human_size(done), human_size(rate)),
+  }
+  dump_error!( std::io::stdout().flush() );
+}
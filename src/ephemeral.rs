@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::disks::*;
+use crate::qmp::*;
+
+/// Creates a throwaway qcow2 overlay next to the other flag files, backed by disk_image.
+/// The caller boots from the returned path and deletes it on exit.
+pub async fn create_ephemeral_overlay(vm: &VMBlock) -> Result<PathBuf, Box<dyn std::error::Error>> {
+  let overlay = vm.flag_path(".ephemeral.qcow2");
+  if overlay.exists() {
+    println!("Removing stale ephemeral overlay {}", overlay.display());
+    tokio::fs::remove_file(&overlay).await?;
+  }
+
+  // qemu-img resolves relative backing files against the overlay's directory, so hand it an absolute one
+  let backing_file = tokio::fs::canonicalize(&vm.disk_image).await?;
+  run_qemu_img(&["create", "-f", "qcow2", "-F", "qcow2", "-b", &backing_file.to_string_lossy(), &overlay.to_string_lossy()]).await?;
+
+  println!("Ephemeral mode: changes go to {} and are discarded on exit, run `commit` to keep them", overlay.display());
+  Ok(overlay)
+}
+
+/// Merges the ephemeral overlay back into disk_image with an active block-commit
+pub async fn commit_command(vm_config: &VMConfig) -> Result<(), Box<dyn std::error::Error>> {
+  let overlay = vm_config.vm.flag_path(".ephemeral.qcow2");
+  if !overlay.exists() {
+    return Err("commit only applies to VMs booted with --ephemeral or ephemeral = true".into());
+  }
+  let qmp_socket = vm_config.vm.flag_path(".qmp.sock");
+  let qmp = qmp_connect_if_running(&qmp_socket).await?.ok_or("VM is not running, nothing to commit")?;
+
+  println!("Committing {} into {}...", overlay.display(), vm_config.vm.disk_image.display());
+  let job_id = "ephemeral-commit".to_string();
+  #[allow(deprecated)] // base and top still have to be spelled out, block_commit has no Default
+  qmp.execute(qmp::block_commit {
+    device: "root_disk".to_string(),
+    job_id: Some(job_id.clone()),
+    auto_dismiss: Some(false), // Keep a failed job around long enough to read its error
+    auto_finalize: None, backing_file: None, base: None, base_node: None,
+    filter_node_name: None, on_error: None, speed: None, top: None, top_node: None,
+  }).await?;
+  qmp_wait_for_job(&qmp, &job_id).await?;
+
+  println!("Done! The VM now writes straight to {}, further changes are no longer ephemeral.", vm_config.vm.disk_image.display());
+  Ok(())
+}
@@ -0,0 +1,79 @@
+use std::io::Write;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::structs::*;
+
+// TPKT header + X.224 Connection Request + RDP Negotiation Request asking for TLS|CredSSP
+const X224_CONNECTION_REQUEST: [u8; 19] = [
+  0x03, 0x00, 0x00, 0x13,
+  0x0e, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00,
+  0x01, 0x00, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00,
+];
+
+/// Polls the forwarded RDP port until the guest answers with an X.224 Connection Confirm,
+/// printing progress (and whether the guest agent answers guest-ping) while Windows boots.
+/// Returns false if the guest never became ready within rdp_wait_timeout_secs.
+pub async fn wait_for_rdp_ready(vm: &VMBlock) -> bool {
+  if vm.rdp_wait_timeout_secs == 0 {
+    return true;
+  }
+  let port = vm.host_port(3389);
+  let qga_socket = vm.flag_path(".qga.sock");
+  let started = std::time::Instant::now();
+  let mut agent_up = false;
+
+  loop {
+    if rdp_probe(port).await {
+      if started.elapsed().as_secs() > 0 {
+        println!();
+      }
+      return true;
+    }
+    if started.elapsed().as_secs() >= vm.rdp_wait_timeout_secs {
+      println!();
+      eprintln!("Guest is not accepting RDP on 127.0.0.1:{} after {}s, not launching the client (see rdp_wait_timeout_secs)", port, started.elapsed().as_secs());
+      return false;
+    }
+
+    agent_up = agent_up || qga_ping(&qga_socket).await;
+    print!("\rWaiting for RDP on 127.0.0.1:{}... {}s (guest agent {})   ", port, started.elapsed().as_secs(),
+      if agent_up { "up, Windows is starting services" } else { "not answering yet" });
+    dump_error!( std::io::stdout().flush() );
+
+    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+  }
+}
+
+/// slirp accepts the TCP connection even when nothing listens in the guest, so only a real
+/// Connection Confirm (TPKT version 3, X.224 code 0xD0) counts.
+pub async fn rdp_probe(port: u16) -> bool {
+  let probe = async {
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(&X224_CONNECTION_REQUEST).await?;
+    let mut reply = [0u8; 6];
+    stream.read_exact(&mut reply).await?;
+    Ok::<bool, std::io::Error>(reply[0] == 0x03 && reply[5] & 0xf0 == 0xd0)
+  };
+  matches!(tokio::time::timeout(tokio::time::Duration::from_secs(3), probe).await, Ok(Ok(true)))
+}
+
+/// Same problem for SSH, but there the server speaks first: only an SSH- banner counts
+pub async fn ssh_probe(port: u16) -> bool {
+  let probe = async {
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut banner = [0u8; 4];
+    stream.read_exact(&mut banner).await?;
+    Ok::<bool, std::io::Error>(&banner == b"SSH-")
+  };
+  matches!(tokio::time::timeout(tokio::time::Duration::from_secs(3), probe).await, Ok(Ok(true)))
+}
+
+pub async fn qga_ping(qga_socket: &std::path::Path) -> bool {
+  let ping = async {
+    let (qga, _handle) = qapi::futures::QgaStreamTokio::open_uds(qga_socket).await.ok()?.spawn_tokio();
+    qga.execute(qapi::qga::guest_ping { }).await.ok()
+  };
+  // Nothing answers on the virtio-serial port until the agent starts, so this needs a timeout
+  matches!(tokio::time::timeout(tokio::time::Duration::from_secs(1), ping).await, Ok(Some(_)))
+}
@@ -0,0 +1,167 @@
+use std::path::Path;
+
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::qmp::*;
+
+// Every run records its hardware fingerprint here; a hibernated state is only valid for the same hardware
+const QEMU_ARGS_FLAG: &str = ".qemu-args.json";
+const HIBERNATE_STATE_FLAG: &str = ".hibernate.state.gz";
+const HIBERNATE_ARGS_FLAG: &str = ".hibernate.args.json";
+
+// Host-side options whose values can change between runs without the guest noticing
+const HOST_ONLY_OPTIONS: [&str; 4] = ["-qmp", "-spice", "-vnc", "-display"];
+
+/// Remembers the hardware QEMU was launched with so hibernate can fingerprint it
+pub async fn record_qemu_args(vm: &VMBlock, qemu_args: &[String]) {
+  dump_error!( tokio::fs::write(vm.flag_path(QEMU_ARGS_FLAG), serde_json::to_string(&hardware_fingerprint(qemu_args)).unwrap_or_default()).await );
+}
+
+/// The args minus host-only settings, so a VM hibernated under `serve` (ports shifted) or with
+/// another display mode still resumes
+fn hardware_fingerprint(qemu_args: &[String]) -> Vec<String> {
+  let mut fingerprint = vec![];
+  let mut args = qemu_args.iter();
+  while let Some(arg) = args.next() {
+    if HOST_ONLY_OPTIONS.contains(&arg.as_str()) {
+      args.next();
+      continue;
+    }
+    if arg == "-nic" {
+      fingerprint.push(arg.clone());
+      if let Some(nic) = args.next() {
+        fingerprint.push(nic.split(',').filter(|opt| !opt.starts_with("hostfwd=")).collect::<Vec<_>>().join(","));
+      }
+      continue;
+    }
+    fingerprint.push(arg.clone());
+  }
+  fingerprint
+}
+
+/// Offline changes to the disks (snapshot revert, resize, compact, restore) make a saved RAM
+/// image describe a disk that no longer exists, so it has to go
+pub async fn invalidate_hibernated_state(vm: &VMBlock) {
+  if vm.flag_path(HIBERNATE_STATE_FLAG).exists() {
+    println!("Discarding the hibernated state of {}, it no longer matches the disks", vm.name);
+    discard_hibernated_state(vm).await;
+  }
+}
+
+/// hibernate: pause the VM, stream its RAM + device state to a compressed file next to the disk, then quit QEMU
+pub async fn hibernate_command(vm_config: &VMConfig) -> Result<(), Box<dyn std::error::Error>> {
+  let qmp = qmp_connect_if_running(&vm_config.vm.flag_path(".qmp.sock")).await?.ok_or("VM is not running, nothing to hibernate")?;
+  if vm_config.vm.flag_path(".ephemeral.qcow2").exists() {
+    return Err("Refusing to hibernate an ephemeral VM, its overlay is deleted on exit so the saved state could never resume".into());
+  }
+
+  let state_file = vm_config.vm.flag_path(HIBERNATE_STATE_FLAG);
+  let tmp_state_file = vm_config.vm.flag_path(".hibernate.state.gz.part");
+
+  // Before touching the guest, so nothing after the migration can fail and strand it paused
+  tokio::fs::copy(vm_config.vm.flag_path(QEMU_ARGS_FLAG), vm_config.vm.flag_path(HIBERNATE_ARGS_FLAG)).await
+    .map_err(|e| format!("Cannot hibernate, this run's hardware fingerprint {} is missing (install phase?): {}", vm_config.vm.flag_path(QEMU_ARGS_FLAG).display(), e))?;
+
+  println!("Pausing {}...", vm_config.vm.name);
+  qmp.execute(qmp::stop { }).await?;
+
+  println!("Writing VM state to {}...", state_file.display());
+  qmp.execute(qmp::migrate {
+    uri: format!("exec:gzip -1 -c > {}", shell_quote_path(&tmp_state_file)),
+    blk: None, detach: None, inc: None, resume: None,
+  }).await?;
+  let saved = match wait_for_migration(&qmp).await {
+    Ok(()) => tokio::fs::rename(&tmp_state_file, &state_file).await.map_err(|e| e.into()),
+    Err(e) => Err(e),
+  };
+  if let Err(e) = saved {
+    // Leave the guest how we found it
+    dump_error!( qmp.execute(qmp::cont { }).await );
+    dump_error!( tokio::fs::remove_file(&tmp_state_file).await );
+    discard_hibernated_state(&vm_config.vm).await;
+    return Err(e);
+  }
+
+  println!("Hibernated, the next run will resume from {}", state_file.display());
+  // QEMU exits before answering quit, so the connection error here is expected
+  let _ = qmp.execute(qmp::quit { }).await;
+  Ok(())
+}
+
+/// Extra args to resume a hibernated VM, if there is a saved state which matches qemu_args.
+/// A state saved with different hardware is deleted since loading it would crash the guest.
+pub async fn resume_args(vm: &VMBlock, qemu_args: &[String]) -> Vec<String> {
+  let state_file = vm.flag_path(HIBERNATE_STATE_FLAG);
+  if !state_file.exists() {
+    return vec![];
+  }
+
+  let saved_args: Vec<String> = match tokio::fs::read(vm.flag_path(HIBERNATE_ARGS_FLAG)).await {
+    Ok(saved_args_bytes) => serde_json::from_slice(&saved_args_bytes).unwrap_or_default(),
+    Err(_) => vec![],
+  };
+  if saved_args != hardware_fingerprint(qemu_args) {
+    println!("Discarding hibernated state {}, the VM's hardware config changed since it was saved", state_file.display());
+    discard_hibernated_state(vm).await;
+    return vec![];
+  }
+
+  println!("Resuming from hibernated state {}", state_file.display());
+  vec!["-incoming".to_string(), format!("exec:gzip -dc {}", shell_quote_path(&state_file))]
+}
+
+/// Waits for the incoming migration to load, un-pauses the guest, and drops the now-stale state file
+pub async fn finish_resume(vm: &VMBlock) -> Result<(), Box<dyn std::error::Error>> {
+  let qmp_socket = vm.flag_path(".qmp.sock");
+  let mut qmp = None;
+  for _ in 0..50 {
+    if let Ok(Some(connected)) = qmp_connect_if_running(&qmp_socket).await {
+      qmp = Some(connected);
+      break;
+    }
+    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+  }
+  let qmp = qmp.ok_or("QEMU never opened its QMP socket while resuming")?;
+
+  let result = wait_for_migration(&qmp).await;
+  // Once the guest has run, the disks no longer match the saved RAM; a state that failed to load is useless too
+  discard_hibernated_state(vm).await;
+  result?;
+
+  qmp.execute(qmp::cont { }).await?;
+  println!("Resumed!");
+  Ok(())
+}
+
+async fn wait_for_migration(qmp: &QmpHandle) -> Result<(), Box<dyn std::error::Error>> {
+  loop {
+    let info = qmp.execute(qmp::query_migrate { }).await?;
+    match info.status {
+      Some(qmp::MigrationStatus::completed) => return Ok(()),
+      Some(qmp::MigrationStatus::failed) | Some(qmp::MigrationStatus::cancelled) => {
+        return Err(format!("Migration failed: {}", info.error_desc.unwrap_or_default()).into());
+      }
+      _ => {
+        if let Some(ram) = info.ram {
+          print!("\r{}MB transferred   ", ram.transferred / (1024 * 1024));
+          dump_error!( std::io::Write::flush(&mut std::io::stdout()) );
+        }
+      }
+    }
+    tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+  }
+}
+
+async fn discard_hibernated_state(vm: &VMBlock) {
+  for flag in [HIBERNATE_STATE_FLAG, HIBERNATE_ARGS_FLAG] {
+    let flag_file = vm.flag_path(flag);
+    if flag_file.exists() {
+      dump_error!( tokio::fs::remove_file(&flag_file).await );
+    }
+  }
+}
+
+fn shell_quote_path(path: &Path) -> String {
+  format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
@@ -0,0 +1,150 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::qmp::*;
+
+// Set while the VM is stopped by the idle watcher, so only our own pauses get auto-resumed
+static IDLE_PAUSED: once_cell::sync::Lazy<std::sync::atomic::AtomicBool> = once_cell::sync::Lazy::new(||
+  std::sync::atomic::AtomicBool::new( false )
+);
+
+// Unix seconds of the last time a client was seen (or the VM was resumed by hand)
+static LAST_ACTIVITY_UNIX: once_cell::sync::Lazy<std::sync::atomic::AtomicU64> = once_cell::sync::Lazy::new(||
+  std::sync::atomic::AtomicU64::new( 0 )
+);
+
+/// pause: freeze the guest's vCPUs, it keeps its RAM but stops using CPU
+pub async fn pause_command(vm_config: &VMConfig) -> Result<(), Box<dyn std::error::Error>> {
+  let qmp = qmp_connect_if_running(&vm_config.vm.flag_path(".qmp.sock")).await?.ok_or("VM is not running")?;
+  let status = qmp.execute(QueryStatus { }).await?;
+  if !status.running {
+    return Err(format!("{} is not running guest code (status: {})", vm_config.vm.name, status.status).into());
+  }
+  qmp.execute(qmp::stop { }).await?;
+  println!("Paused {}, `resume` to continue", vm_config.vm.name);
+  Ok(())
+}
+
+/// resume: un-freeze a VM paused by `pause` or by idle_suspend_minutes
+pub async fn resume_command(vm_config: &VMConfig) -> Result<(), Box<dyn std::error::Error>> {
+  let qmp = qmp_connect_if_running(&vm_config.vm.flag_path(".qmp.sock")).await?.ok_or("VM is not running")?;
+  qmp.execute(qmp::cont { }).await?;
+  IDLE_PAUSED.store(false, Ordering::SeqCst);
+  LAST_ACTIVITY_UNIX.store(unix_now(), Ordering::SeqCst);
+  println!("Resumed {}", vm_config.vm.name);
+  Ok(())
+}
+
+/// Host ports QEMU forwards into the guest, taken from hostfwd=tcp:ADDR:PORT-... in the qemu args
+pub fn hostfwd_ports(qemu_args: &[String]) -> Vec<u16> {
+  let mut ports = vec![];
+  for arg in qemu_args.iter() {
+    for opt in arg.split(',') {
+      if let Some(fwd) = opt.strip_prefix("hostfwd=tcp:") {
+        // ADDR:PORT-GUESTADDR:GUESTPORT, ADDR may be empty
+        let host_side = fwd.split('-').next().unwrap_or_default();
+        if let Some(port) = host_side.rsplit(':').next().and_then(|p| p.parse().ok()) {
+          ports.push(port);
+        }
+      }
+    }
+  }
+  ports
+}
+
+/// Pauses the VM once no RDP/SSH/SPICE client has been connected for idle_minutes and resumes it
+/// on the next connection attempt. A stopped guest's slirp still accepts host-side connections,
+/// so new clients show up in /proc/net even while paused. Returns when QEMU goes away.
+pub async fn idle_suspend_task(qmp_socket: PathBuf, ports: Vec<u16>, spice_socket: PathBuf, idle_minutes: usize) {
+  let idle_secs = idle_minutes as u64 * 60;
+  LAST_ACTIVITY_UNIX.store(unix_now(), Ordering::SeqCst);
+  println!("Pausing the VM after {} idle minutes without clients on ports {:?} or {}", idle_minutes, ports, spice_socket.display());
+
+  let mut ticks: u64 = 0;
+  loop {
+    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+    ticks += 1;
+
+    let clients = count_tcp_clients(&ports).await + count_unix_clients(&spice_socket).await;
+    if clients > 0 {
+      LAST_ACTIVITY_UNIX.store(unix_now(), Ordering::SeqCst);
+    }
+    let paused_by_us = IDLE_PAUSED.load(Ordering::SeqCst);
+    let idle_for = unix_now().saturating_sub(LAST_ACTIVITY_UNIX.load(Ordering::SeqCst));
+
+    // Only talk to QMP when something may need doing, it serves a single client at a time
+    let wake = paused_by_us && clients > 0;
+    let sleep = !paused_by_us && idle_for >= idle_secs;
+    let recheck = paused_by_us && ticks.is_multiple_of(15); // Someone may have resumed it from another process
+    if !(wake || sleep || recheck) {
+      continue;
+    }
+
+    let qmp = match qmp_connect_if_running(&qmp_socket).await {
+      Ok(Some(qmp)) => qmp,
+      Ok(None) => return,
+      Err(_) => continue, // Busy with another command, try again next tick
+    };
+    let running = match qmp.execute(QueryStatus { }).await {
+      Ok(status) => status.running,
+      Err(_) => continue,
+    };
+
+    if wake {
+      if !running {
+        println!("Client connected, resuming idle VM");
+        dump_error!( qmp.execute(qmp::cont { }).await );
+      }
+      IDLE_PAUSED.store(false, Ordering::SeqCst);
+    }
+    else if sleep {
+      if running {
+        println!("No clients for {} minutes, pausing the VM (it resumes on the next RDP/SSH/SPICE connection)", idle_minutes);
+        dump_error!( qmp.execute(qmp::stop { }).await );
+        IDLE_PAUSED.store(true, Ordering::SeqCst);
+      }
+      else {
+        // Paused by hand, leave it alone until someone resumes it
+        LAST_ACTIVITY_UNIX.store(unix_now(), Ordering::SeqCst);
+      }
+    }
+    else if running {
+      IDLE_PAUSED.store(false, Ordering::SeqCst);
+      LAST_ACTIVITY_UNIX.store(unix_now(), Ordering::SeqCst);
+    }
+  }
+}
+
+/// Connections to the forwarded ports, including half-open ones (SYN_RECV) so a client knocking
+/// on a paused VM counts before the guest ever answers.
+async fn count_tcp_clients(ports: &[u16]) -> usize {
+  let mut clients = 0;
+  for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+    let contents = tokio::fs::read_to_string(table).await.unwrap_or_default();
+    for line in contents.lines().skip(1) {
+      let cols: Vec<&str> = line.split_whitespace().collect();
+      if cols.len() < 4 {
+        continue;
+      }
+      let local_port = cols[1].rsplit(':').next().and_then(|p| u16::from_str_radix(p, 16).ok()).unwrap_or(0);
+      // 01 = ESTABLISHED, 03 = SYN_RECV
+      if ports.contains(&local_port) && (cols[3] == "01" || cols[3] == "03") {
+        clients += 1;
+      }
+    }
+  }
+  clients
+}
+
+/// Accepted connections on the SPICE socket; they share the listening socket's path in /proc/net/unix
+async fn count_unix_clients(socket: &Path) -> usize {
+  let socket = socket.to_string_lossy();
+  let contents = tokio::fs::read_to_string("/proc/net/unix").await.unwrap_or_default();
+  contents.lines().skip(1)
+    .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+    .filter(|cols| cols.len() >= 8 && cols[5] == "03" && cols[7] == socket) // 03 = connected
+    .count()
+}
@@ -0,0 +1,90 @@
+use std::io::Write;
+use std::path::Path;
+
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::guest_ready::*;
+use crate::qmp::*;
+use crate::console::*;
+
+// Device ids given to the install-only CD-ROMs so they can be ejected
+pub const INSTALL_MEDIA_DRIVE_IDS: [&str; 2] = ["install_media", "unattend_media"];
+
+// A line the installed system writes to ttyS0 / COM1 once it is set up, eg from a first-boot service
+pub const INSTALL_DONE_SERIAL_MARKER: &str = "AZURE-VM-INSTALL-DONE";
+
+const POWERDOWN_TIMEOUT_SECS: u64 = 180;
+
+/// Resolves done_when = "auto" to the signals only an installed system gives: the guest agent
+/// (installed last by [unattend]) or the serial marker. Installers often run sshd or RDP
+/// themselves, so those are only used when asked for by name.
+pub fn install_done_signals(vm_config: &VMConfig) -> Vec<InstallDoneSignal> {
+  match vm_config.install.done_when {
+    InstallDoneSignal::Auto => vec![InstallDoneSignal::Qga, InstallDoneSignal::Serial],
+    done_when => vec![done_when],
+  }
+}
+
+/// Polls until one of the signals fires, or forever for done_when = "manual"
+pub async fn wait_for_install_done(vm_config: &VMConfig) -> InstallDoneSignal {
+  let signals = install_done_signals(vm_config);
+  let qga_socket = vm_config.vm.flag_path(".qga.sock");
+  let serial_log = serial_log_path(&vm_config.vm);
+  // Only what this install writes counts, the log is appended to across runs
+  let serial_log_start = tokio::fs::metadata(&serial_log).await.map(|m| m.len()).unwrap_or(0);
+  loop {
+    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+    for signal in signals.iter() {
+      let done = match signal {
+        InstallDoneSignal::Qga => qga_ping(&qga_socket).await,
+        InstallDoneSignal::Serial => serial_log_has_marker(&serial_log, serial_log_start).await,
+        InstallDoneSignal::Rdp => rdp_probe(vm_config.vm.host_port(3389)).await,
+        InstallDoneSignal::Ssh => ssh_probe(vm_config.vm.host_port(2222)).await,
+        InstallDoneSignal::Auto | InstallDoneSignal::Manual => false,
+      };
+      if done {
+        return *signal;
+      }
+    }
+  }
+}
+
+async fn serial_log_has_marker(serial_log: &Path, start: u64) -> bool {
+  match tokio::fs::read(serial_log).await {
+    Ok(log) => log.get(start as usize..).is_some_and(|new_output| {
+      String::from_utf8_lossy(new_output).contains(INSTALL_DONE_SERIAL_MARKER)
+    }),
+    Err(_) => false,
+  }
+}
+
+/// Ejects the install media so a reboot cannot land in Setup again, then powers the guest down
+/// through ACPI and waits for QEMU to exit, killing it if the guest ignores the request.
+pub async fn finish_install(qmp_socket: &Path, install_proc: &mut tokio::process::Child) -> Result<(), Box<dyn std::error::Error>> {
+  let qmp = qmp_connect_if_running(qmp_socket).await?.ok_or("install VM is not running")?;
+  for drive_id in INSTALL_MEDIA_DRIVE_IDS.iter() {
+    #[allow(deprecated)] // The struct literal still has to name the deprecated device field
+    let ejected = qmp.execute(qmp::eject { device: None, id: Some(drive_id.to_string()), force: Some(true) }).await;
+    if ejected.is_ok() {
+      println!("Ejected {}", drive_id);
+    }
+  }
+  qmp.execute(qmp::system_powerdown { }).await?;
+  drop(qmp);
+
+  let started = std::time::Instant::now();
+  while started.elapsed().as_secs() < POWERDOWN_TIMEOUT_SECS {
+    if install_proc.try_wait()?.is_some() {
+      println!();
+      return Ok(());
+    }
+    print!("\rWaiting for the install VM to power down... {}s   ", started.elapsed().as_secs());
+    dump_error!( std::io::stdout().flush() );
+    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+  }
+  println!();
+  eprintln!("Install VM did not power down within {}s, killing it", POWERDOWN_TIMEOUT_SECS);
+  install_proc.kill().await?;
+  Ok(())
+}
@@ -0,0 +1,196 @@
+// Just enough ISO9660 + Joliet to hand a few small files to a guest on a virtual CD-ROM
+// (autounattend.xml for Windows Setup, user-data/meta-data for cloud-init), without mkisofs.
+// Every file sits in the root directory. Linux and Windows both read the Joliet names, which
+// keep case and dashes; the primary names are only there for readers that ignore Joliet.
+
+const SECTOR: usize = 2048;
+
+// Sector layout, all fixed because there is only ever one directory
+const PVD_LBA: usize = 16;
+const JOLIET_SVD_LBA: usize = 17;
+const TERMINATOR_LBA: usize = 18;
+const PRIMARY_L_PATH_TABLE_LBA: usize = 19;
+const PRIMARY_M_PATH_TABLE_LBA: usize = 20;
+const JOLIET_L_PATH_TABLE_LBA: usize = 21;
+const JOLIET_M_PATH_TABLE_LBA: usize = 22;
+const PRIMARY_ROOT_LBA: usize = 23;
+const JOLIET_ROOT_LBA: usize = 24;
+const FIRST_FILE_LBA: usize = 25;
+
+/// Builds an ISO image holding files (name, contents) in its root directory
+pub fn build_iso(volume_id: &str, files: &[(String, Vec<u8>)]) -> Result<Vec<u8>, String> {
+  let recorded_at = recording_date(std::time::SystemTime::now());
+
+  // Place file data after the fixed sectors
+  let mut extents = vec![];
+  let mut next_lba = FIRST_FILE_LBA;
+  for (_name, contents) in files.iter() {
+    extents.push(next_lba);
+    next_lba += contents.len().div_ceil(SECTOR).max(1);
+  }
+  let total_sectors = next_lba;
+
+  let mut primary_entries: Vec<(Vec<u8>, usize, usize)> = vec![];
+  let mut joliet_entries: Vec<(Vec<u8>, usize, usize)> = vec![];
+  for ((name, contents), lba) in files.iter().zip(extents.iter()) {
+    if name.is_empty() || name.len() > 64 {
+      return Err(format!("ISO file name {:?} must be 1 to 64 characters", name));
+    }
+    primary_entries.push((primary_file_id(name), *lba, contents.len()));
+    joliet_entries.push((ucs2be(&format!("{};1", name)), *lba, contents.len()));
+  }
+  // Directory records must be sorted by identifier
+  primary_entries.sort();
+  joliet_entries.sort();
+
+  let mut image = vec![0u8; total_sectors * SECTOR];
+  let root_record = |lba: usize| dir_record(&[0], lba, SECTOR, true, &recorded_at);
+
+  write_volume_descriptor(&mut image[PVD_LBA * SECTOR..], false, volume_id, total_sectors, PRIMARY_L_PATH_TABLE_LBA, PRIMARY_M_PATH_TABLE_LBA, &root_record(PRIMARY_ROOT_LBA));
+  write_volume_descriptor(&mut image[JOLIET_SVD_LBA * SECTOR..], true, volume_id, total_sectors, JOLIET_L_PATH_TABLE_LBA, JOLIET_M_PATH_TABLE_LBA, &root_record(JOLIET_ROOT_LBA));
+
+  let terminator = &mut image[TERMINATOR_LBA * SECTOR..];
+  terminator[0] = 255;
+  terminator[1..6].copy_from_slice(b"CD001");
+  terminator[6] = 1;
+
+  for (l_lba, m_lba, root_lba) in [
+    (PRIMARY_L_PATH_TABLE_LBA, PRIMARY_M_PATH_TABLE_LBA, PRIMARY_ROOT_LBA),
+    (JOLIET_L_PATH_TABLE_LBA, JOLIET_M_PATH_TABLE_LBA, JOLIET_ROOT_LBA),
+  ] {
+    write_path_table(&mut image[l_lba * SECTOR..], root_lba as u32, false);
+    write_path_table(&mut image[m_lba * SECTOR..], root_lba as u32, true);
+  }
+
+  for (root_lba, entries) in [(PRIMARY_ROOT_LBA, &primary_entries), (JOLIET_ROOT_LBA, &joliet_entries)] {
+    let mut dir = vec![];
+    dir.extend(dir_record(&[0], root_lba, SECTOR, true, &recorded_at)); // .
+    dir.extend(dir_record(&[1], root_lba, SECTOR, true, &recorded_at)); // ..
+    for (file_id, lba, len) in entries.iter() {
+      dir.extend(dir_record(file_id, *lba, *len, false, &recorded_at));
+    }
+    if dir.len() > SECTOR {
+      return Err(format!("{} files do not fit in a single ISO directory sector", files.len()));
+    }
+    image[root_lba * SECTOR..root_lba * SECTOR + dir.len()].copy_from_slice(&dir);
+  }
+
+  for ((_name, contents), lba) in files.iter().zip(extents.iter()) {
+    image[lba * SECTOR..lba * SECTOR + contents.len()].copy_from_slice(contents);
+  }
+  Ok(image)
+}
+
+fn write_volume_descriptor(sector: &mut [u8], joliet: bool, volume_id: &str, total_sectors: usize, l_path_table_lba: usize, m_path_table_lba: usize, root_record: &[u8]) {
+  let text = |s: &str, len: usize| -> Vec<u8> {
+    if joliet {
+      // UCS-2 padded with UCS-2 spaces; odd lengths end with a single zero byte
+      let mut field = ucs2be(s);
+      field.truncate(len & !1);
+      while field.len() + 1 < len {
+        field.extend([0, b' ']);
+      }
+      field.resize(len, 0);
+      field
+    }
+    else {
+      let mut field: Vec<u8> = s.to_uppercase().bytes().take(len).collect();
+      field.resize(len, b' ');
+      field
+    }
+  };
+
+  sector[0] = if joliet { 2 } else { 1 };
+  sector[1..6].copy_from_slice(b"CD001");
+  sector[6] = 1;
+  sector[8..40].copy_from_slice(&text("LINUX", 32));
+  sector[40..72].copy_from_slice(&text(volume_id, 32));
+  sector[80..88].copy_from_slice(&both_endian_u32(total_sectors as u32));
+  if joliet {
+    sector[88..91].copy_from_slice(b"%/E"); // UCS-2 level 3
+  }
+  sector[120..124].copy_from_slice(&both_endian_u16(1)); // Volume set size
+  sector[124..128].copy_from_slice(&both_endian_u16(1)); // Volume sequence number
+  sector[128..132].copy_from_slice(&both_endian_u16(SECTOR as u16));
+  sector[132..140].copy_from_slice(&both_endian_u32(PATH_TABLE_LEN as u32));
+  sector[140..144].copy_from_slice(&(l_path_table_lba as u32).to_le_bytes());
+  sector[148..152].copy_from_slice(&(m_path_table_lba as u32).to_be_bytes());
+  sector[156..156 + root_record.len()].copy_from_slice(root_record);
+  for (start, len) in [(190, 128), (318, 128), (446, 128), (702, 37), (739, 37), (776, 37)] {
+    sector[start..start + len].copy_from_slice(&text("", len));
+  }
+  sector[574..702].copy_from_slice(&text("AZURE-VM", 128));
+  // Creation, modification, expiration and effective dates left "not specified"
+  for start in [813, 830, 847, 864] {
+    sector[start..start + 16].copy_from_slice(b"0000000000000000");
+  }
+  sector[881] = 1; // File structure version
+}
+
+// One entry (the root) of 8 + 1 byte name + 1 pad byte
+const PATH_TABLE_LEN: usize = 10;
+
+fn write_path_table(sector: &mut [u8], root_lba: u32, big_endian: bool) {
+  sector[0] = 1; // Identifier length
+  sector[2..6].copy_from_slice(&if big_endian { root_lba.to_be_bytes() } else { root_lba.to_le_bytes() });
+  sector[6..8].copy_from_slice(&if big_endian { 1u16.to_be_bytes() } else { 1u16.to_le_bytes() }); // Parent is itself
+}
+
+fn dir_record(file_id: &[u8], lba: usize, len: usize, is_dir: bool, recorded_at: &[u8; 7]) -> Vec<u8> {
+  let mut record = vec![0u8; 33];
+  record[2..10].copy_from_slice(&both_endian_u32(lba as u32));
+  record[10..18].copy_from_slice(&both_endian_u32(len as u32));
+  record[18..25].copy_from_slice(recorded_at);
+  record[25] = if is_dir { 2 } else { 0 };
+  record[28..32].copy_from_slice(&both_endian_u16(1));
+  record[32] = file_id.len() as u8;
+  record.extend(file_id);
+  if record.len() % 2 == 1 {
+    record.push(0);
+  }
+  record[0] = record.len() as u8;
+  record
+}
+
+// d-characters only: uppercase, digits and _, as NAME.EXT;1
+fn primary_file_id(name: &str) -> Vec<u8> {
+  let (stem, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+  let d_chars = |s: &str| -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect()
+  };
+  format!("{}.{};1", d_chars(stem), d_chars(ext)).into_bytes()
+}
+
+fn ucs2be(s: &str) -> Vec<u8> {
+  s.encode_utf16().flat_map(|unit| unit.to_be_bytes()).collect()
+}
+
+fn both_endian_u16(val: u16) -> [u8; 4] {
+  let (le, be) = (val.to_le_bytes(), val.to_be_bytes());
+  [le[0], le[1], be[0], be[1]]
+}
+
+fn both_endian_u32(val: u32) -> [u8; 8] {
+  let (le, be) = (val.to_le_bytes(), val.to_be_bytes());
+  [le[0], le[1], le[2], le[3], be[0], be[1], be[2], be[3]]
+}
+
+// Years since 1900, month, day, hour, minute, second, UTC offset
+fn recording_date(time: std::time::SystemTime) -> [u8; 7] {
+  let secs = time.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+  // Days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  [
+    (year - 1900).clamp(0, 255) as u8, month as u8, day as u8,
+    (secs_of_day / 3600) as u8, (secs_of_day / 60 % 60) as u8, (secs_of_day % 60) as u8, 0,
+  ]
+}
@@ -0,0 +1,107 @@
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::qmp::*;
+
+// Gap between typed characters; some guests drop keys that arrive in the same frame
+const TYPE_KEY_DELAY_MS: u64 = 15;
+
+/// sendkey ctrl-alt-delete: presses the dash-separated QEMU key names together, then releases them
+pub async fn sendkey_command(vm: &VMBlock, combo: &str) -> Result<(), Box<dyn std::error::Error>> {
+  if combo.is_empty() {
+    return Err("Usage: sendkey KEY[-KEY...], eg sendkey ctrl-alt-delete".into());
+  }
+  let mut keys = vec![];
+  for name in combo.split('-') {
+    keys.push(qcode_key(key_alias(name))?);
+  }
+  let qmp = qmp_connect_if_running(&vm.flag_path(".qmp.sock")).await?.ok_or("VM is not running")?;
+  qmp.execute(qmp::send_key { keys, hold_time: None }).await?;
+  Ok(())
+}
+
+/// type "text": types text on a US keyboard layout; \n presses enter and \t tab
+pub async fn type_command(vm: &VMBlock, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+  let text = text.replace("\\n", "\n").replace("\\t", "\t");
+  // Check everything up front rather than typing half of it
+  let mut strokes = vec![];
+  for c in text.chars() {
+    strokes.push(char_key(c).ok_or_else(|| format!("Cannot type {:?} with a US keyboard layout", c))?);
+  }
+
+  let qmp = qmp_connect_if_running(&vm.flag_path(".qmp.sock")).await?.ok_or("VM is not running")?;
+  for (name, shift) in strokes {
+    let key = qcode_key(name)?;
+    let shift_key = qcode_key("shift")?;
+    let mut events = vec![];
+    if shift {
+      events.push(key_event(shift_key.clone(), true));
+    }
+    events.push(key_event(key.clone(), true));
+    events.push(key_event(key, false));
+    if shift {
+      events.push(key_event(shift_key, false));
+    }
+    qmp.execute(qmp::input_send_event { events, device: None, head: None }).await?;
+    tokio::time::sleep(tokio::time::Duration::from_millis(TYPE_KEY_DELAY_MS)).await;
+  }
+  Ok(())
+}
+
+fn qcode_key(name: &str) -> Result<qmp::KeyValue, Box<dyn std::error::Error>> {
+  let code: qmp::QKeyCode = name.parse().map_err(|_| format!("Unknown key {:?}, see QEMU's QKeyCode list", name))?;
+  Ok(qmp::KeyValue::qcode(qmp::QKeyCodeWrapper { data: code }))
+}
+
+fn key_event(key: qmp::KeyValue, down: bool) -> qmp::InputEvent {
+  qmp::InputEvent::key(qmp::InputKeyEventWrapper { data: qmp::InputKeyEvent { down, key } })
+}
+
+// Friendlier names for the keys people reach for most
+fn key_alias(name: &str) -> &str {
+  match name {
+    "del" => "delete",
+    "enter" | "return" => "ret",
+    "space" => "spc",
+    "escape" => "esc",
+    "win" | "super" => "meta_l",
+    "pageup" => "pgup",
+    "pagedown" => "pgdn",
+    other => other,
+  }
+}
+
+// QKeyCode name and whether shift is held, for a US layout
+fn char_key(c: char) -> Option<(&'static str, bool)> {
+  const LETTERS: [&str; 26] = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m",
+    "n", "o", "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z"];
+  const DIGITS: [&str; 10] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+  if c.is_ascii_lowercase() {
+    return Some((LETTERS[(c as u8 - b'a') as usize], false));
+  }
+  if c.is_ascii_uppercase() {
+    return Some((LETTERS[(c as u8 - b'A') as usize], true));
+  }
+  if c.is_ascii_digit() {
+    return Some((DIGITS[(c as u8 - b'0') as usize], false));
+  }
+  Some(match c {
+    ' ' => ("spc", false),
+    '\n' => ("ret", false),
+    '\t' => ("tab", false),
+    '-' => ("minus", false), '_' => ("minus", true),
+    '=' => ("equal", false), '+' => ("equal", true),
+    '[' => ("bracket_left", false), '{' => ("bracket_left", true),
+    ']' => ("bracket_right", false), '}' => ("bracket_right", true),
+    '\\' => ("backslash", false), '|' => ("backslash", true),
+    ';' => ("semicolon", false), ':' => ("semicolon", true),
+    '\'' => ("apostrophe", false), '"' => ("apostrophe", true),
+    '`' => ("grave_accent", false), '~' => ("grave_accent", true),
+    ',' => ("comma", false), '<' => ("comma", true),
+    '.' => ("dot", false), '>' => ("dot", true),
+    '/' => ("slash", false), '?' => ("slash", true),
+    '!' => ("1", true), '@' => ("2", true), '#' => ("3", true), '$' => ("4", true), '%' => ("5", true),
+    '^' => ("6", true), '&' => ("7", true), '*' => ("8", true), '(' => ("9", true), ')' => ("0", true),
+    _ => return None,
+  })
+}
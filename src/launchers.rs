@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+use crate::structs::*;
+
+/// export-launchers CONFIG [--icons]
+/// Writes one XDG .desktop file per [[apps]] entry; each runs `azure-vm CONFIG rdp APP`, which boots
+/// the VM in the background if needed. --icons pulls each program's icon out of the running guest.
+pub async fn export_launchers_command(vm_config: &VMConfig, config_path: &Path, icons: bool) -> Result<(), Box<dyn std::error::Error>> {
+  if vm_config.apps.is_empty() {
+    return Err(format!("{} has no [[apps]] to export", config_path.display()).into());
+  }
+
+  let data_dir = xdg_data_home()?;
+  let applications_dir = data_dir.join("applications");
+  let icons_dir = data_dir.join("azure-vm").join("icons");
+  tokio::fs::create_dir_all(&applications_dir).await?;
+  if icons {
+    tokio::fs::create_dir_all(&icons_dir).await?;
+  }
+
+  // Launchers outlive the shell they were made in, so every path has to be absolute
+  let exe = std::env::current_exe()?;
+  let config_path = tokio::fs::canonicalize(config_path).await?;
+  let vm_stem = config_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+  for app in vm_config.apps.iter() {
+    let launcher_stem = format!("azure-vm-{}-{}", vm_stem, app.name).to_lowercase().replace([' ', '/'], "-");
+
+    let mut icon = "application-x-ms-dos-executable".to_string();
+    if icons {
+      let icon_file = icons_dir.join(format!("{}.png", launcher_stem));
+      // A socket with no agent behind it never answers, hence the timeout
+      match tokio::time::timeout(tokio::time::Duration::from_secs(30), fetch_guest_icon(&vm_config.vm, &app.program)).await {
+        Ok(Ok(png)) => {
+          tokio::fs::write(&icon_file, png).await?;
+          icon = icon_file.to_string_lossy().to_string();
+        }
+        Ok(Err(e)) => eprintln!("No icon for {}: {}", app.name, e),
+        Err(_) => eprintln!("No icon for {}: the guest agent did not answer", app.name),
+      }
+    }
+
+    let exec = [exe.to_string_lossy().to_string(), config_path.to_string_lossy().to_string(), "rdp".to_string(), app.name.clone()]
+      .iter().map(|arg| desktop_exec_quote(arg)).collect::<Vec<_>>().join(" ");
+    let desktop_entry = format!(
+      "[Desktop Entry]\nType=Application\nName={name} ({vm})\nComment={program} on {vm}\nExec={exec}\nIcon={icon}\nTerminal=false\nCategories=Utility;\n",
+      name=app.name, program=app.program.replace('\\', "\\\\"), vm=vm_config.vm.name, exec=exec, icon=icon,
+    );
+    let launcher_file = applications_dir.join(format!("{}.desktop", launcher_stem));
+    tokio::fs::write(&launcher_file, desktop_entry).await?;
+    println!("Wrote {}", launcher_file.display());
+  }
+  Ok(())
+}
+
+fn xdg_data_home() -> Result<PathBuf, Box<dyn std::error::Error>> {
+  if let Ok(data_home) = std::env::var("XDG_DATA_HOME") {
+    if !data_home.is_empty() {
+      return Ok(PathBuf::from(data_home));
+    }
+  }
+  let home = std::env::var("HOME").map_err(|_| "Neither XDG_DATA_HOME nor HOME is set")?;
+  Ok(PathBuf::from(home).join(".local").join("share"))
+}
+
+/// Quotes one argument for the Exec= key of a .desktop file
+fn desktop_exec_quote(arg: &str) -> String {
+  let arg = arg.replace('%', "%%");
+  if !arg.contains(|c: char| c.is_whitespace() || "\"'\\><~|&;$*?#()`".contains(c)) {
+    return arg;
+  }
+  let mut quoted = String::from("\"");
+  for c in arg.chars() {
+    if "\"`$\\".contains(c) {
+      quoted.push('\\');
+    }
+    quoted.push(c);
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Has PowerShell in the guest render the program's icon to a temp PNG, then reads it back over QGA
+async fn fetch_guest_icon(vm: &VMBlock, program: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+  let (qga, _handle) = qapi::futures::QgaStreamTokio::open_uds(vm.flag_path(".qga.sock")).await
+    .map_err(|e| format!("guest agent is not reachable, start the VM first ({})", e))?
+    .spawn_tokio();
+
+  let script = format!(
+    "Add-Type -AssemblyName System.Drawing; $out = Join-Path $env:TEMP 'azure-vm-icon.png'; \
+     [System.Drawing.Icon]::ExtractAssociatedIcon('{}').ToBitmap().Save($out, [System.Drawing.Imaging.ImageFormat]::Png); \
+     Write-Output $out",
+    program.replace('\'', "''"),
+  );
+  let exec = qga.execute(qapi::qga::guest_exec {
+    path: "powershell.exe".to_string(),
+    arg: Some(vec!["-NoProfile".to_string(), "-NonInteractive".to_string(), "-Command".to_string(), script]),
+    capture_output: Some(true),
+    env: None,
+    input_data: None,
+  }).await?;
+
+  let status = loop {
+    let status = qga.execute(qapi::qga::guest_exec_status { pid: exec.pid }).await?;
+    if status.exited {
+      break status;
+    }
+    tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+  };
+  if status.exitcode != Some(0) {
+    return Err(format!("powershell failed: {}", String::from_utf8_lossy(&status.err_data.unwrap_or_default()).trim()).into());
+  }
+  let guest_png = String::from_utf8_lossy(&status.out_data.unwrap_or_default()).trim().to_string();
+
+  let handle = qga.execute(qapi::qga::guest_file_open { path: guest_png, mode: Some("rb".to_string()) }).await?;
+  let mut png = vec![];
+  loop {
+    let chunk = qga.execute(qapi::qga::guest_file_read { handle, count: Some(64 * 1024) }).await?;
+    png.extend(chunk.buf_b64);
+    if chunk.eof || chunk.count == 0 {
+      break;
+    }
+  }
+  qga.execute(qapi::qga::guest_file_close { handle }).await?;
+  Ok(png)
+}
@@ -14,6 +14,59 @@ use misc::*;
 #[macro_use]
 pub mod macros;
 
+mod disks;
+use disks::*;
+
+mod qmp;
+
+mod snapshot;
+use snapshot::*;
+
+mod ephemeral;
+use ephemeral::*;
+
+mod clone;
+use clone::*;
+
+mod disk_maintenance;
+use disk_maintenance::*;
+
+mod backup;
+use backup::*;
+
+mod hibernate;
+use hibernate::*;
+mod idle;
+use idle::*;
+mod serve;
+use serve::*;
+mod guest_ready;
+mod rdp;
+use rdp::*;
+mod launchers;
+use launchers::*;
+mod secrets;
+mod download;
+use download::*;
+mod artifacts;
+use artifacts::*;
+mod iso9660;
+mod unattend;
+use unattend::*;
+mod cloud_init;
+use cloud_init::*;
+mod install_detect;
+use install_detect::*;
+mod display;
+use display::*;
+mod console;
+use console::*;
+mod png;
+mod screenshot;
+use screenshot::*;
+mod keyboard;
+use keyboard::*;
+
 
 fn main() {
   let args: Vec<String> = std::env::args().collect();
@@ -29,89 +82,111 @@ fn main() {
       .build()
       .expect("Could not build tokio runtime!");
 
-    return rt.block_on(vm_manager(first_arg));
+    // Commands which are not about one particular VM
+    if first_arg == "clone" {
+      return rt.block_on(clone_vm(args[2..].to_vec()));
+    }
+    if first_arg == "serve" {
+      return rt.block_on(serve_vm(args[2..].to_vec()));
+    }
+    if first_arg == "artifacts" {
+      return rt.block_on(async { dump_error!( artifacts_command(&args[2..]).await ) });
+    }
+    if first_arg == "export-launchers" {
+      return rt.block_on(export_launchers(args[2..].to_vec()));
+    }
+
+    // --flags directly after the config tweak how the VM runs, anything after them is a one-shot command
+    let mut run_flags: Vec<String> = vec![];
+    let mut rest = args[2..].iter().peekable();
+    while let Some(flag) = rest.next_if(|a| a.starts_with("--")) {
+      run_flags.push(flag.clone());
+      if flag == "--screenshot-every" {
+        run_flags.extend(rest.next().cloned()); // Takes a value
+      }
+    }
+    let command_args: Vec<String> = rest.cloned().collect();
+
+    if !command_args.is_empty() {
+      return rt.block_on(vm_command(first_arg, command_args));
+    }
+
+    return rt.block_on(vm_manager(first_arg, run_flags));
 
   }
 }
 
 fn dump_help() {
   println!(r#"Usage:
-  {exe} /path/to/vm.toml
+  {exe} /path/to/vm.toml [--ephemeral] [--screenshot-every N]
 
     Runs the VM
+      --ephemeral   Boot from a throwaway overlay of disk_image, discarded on exit
+      --screenshot-every N
+                    Keep small PNGs of the screen, one every N seconds, next to the disk image
+
+  {exe} /path/to/vm.toml COMMAND [ARGS...]
+
+    Runs a single REPL command against the VM (running or stopped), eg
+      {exe} tiny11 snapshot create before-update
+
+  {exe} clone /path/to/src.toml NEW_NAME [--flatten]
+
+    Creates NEW_NAME as a linked qcow2 clone of src's disk_image, writing new_name.toml next to src.toml.
+    --flatten copies the data instead so src can keep being booted.
+
+  {exe} serve /path/to/vm.toml
+
+    Listens on the VM's RDP (3389) and SSH (2222) host ports and boots or resumes the VM on the first
+    connection, so RDP shortcuts work on a cold machine.
+
+  {exe} export-launchers /path/to/vm.toml [--icons]
+
+    Writes a ~/.local/share/applications .desktop file for every [[apps]] entry, booting the VM on demand.
+    --icons fetches each program's icon from the running guest through the guest agent.
 
-  {exe} TODO more runtime VM control stuff
+  {exe} artifacts import PATH [URL...] | artifacts list
+
+    Adds a pre-downloaded ISO to the artifact cache, optionally as the content of URL, so VMs can be
+    built without network access. AZURE_VM_MIRROR rewrites download URLs, AZURE_VM_CACHE_DIR moves the cache.
 
 "#,
   exe=std::env::current_exe().unwrap_or(std::path::PathBuf::from("/dev/null")).display()
 );
 }
 
-// We'll auto-download this & re-use for all vms requesting mount_windows_virtio_iso = true
-const VIRTIO_WIN_ISO_URL: &'static str = "https://fedorapeople.org/groups/virt/virtio-win/direct-downloads/stable-virtio/virtio-win.iso";
-const VIRTIO_WIN_ISO_LOCAL_PATH: &'static str = "/mnt/scratch/vms/virtio-win.iso";
-
-async fn ensure_virtio_win_iso_exists() {
-  use futures::StreamExt;
-
-  let virtio_iso_path = std::path::PathBuf::from(VIRTIO_WIN_ISO_LOCAL_PATH);
-  if let Some(virtio_parent_dir) = virtio_iso_path.parent() {
-    println!("Ensuring {} exists...", virtio_parent_dir.display());
-    dump_error!( tokio::fs::create_dir_all(virtio_parent_dir).await );
+// Downloaded once & re-used by all vms requesting mount_windows_virtio_iso = true with the same virtio_win_iso path.
+// Returns true when the ISO is there to attach.
+async fn ensure_virtio_win_iso_exists(vm_config: &VMConfig) -> bool {
+  let vm = &vm_config.vm;
+  if !vm_config.install_needs_virtio_win_iso() && !vm.mount_windows_virtio_iso_at_runtime {
+    return false;
   }
-  if ! virtio_iso_path.exists() {
-    // Download it
-    println!("Downloading {} to {}", VIRTIO_WIN_ISO_URL, VIRTIO_WIN_ISO_LOCAL_PATH);
-    match tokio::fs::File::create(VIRTIO_WIN_ISO_LOCAL_PATH).await {
-      Ok(mut local_virtio_iso_file) => {
-        let conn = reqwest::get(VIRTIO_WIN_ISO_URL).await.expect("Could not connect!");
-        let mut download_stream = conn.bytes_stream();
-
-        while let Some(item) = download_stream.next().await {
-          dump_error!( tokio::io::copy(&mut item.unwrap().as_ref(), &mut local_virtio_iso_file).await );
-        }
-      }
-      Err(e) => {
-        eprintln!("Warning, the following resource will not be available: {} because {:?}", VIRTIO_WIN_ISO_LOCAL_PATH, e);
-      }
+  match download_file(&vm.virtio_win_iso_url, &vm.virtio_win_iso, None).await {
+    Ok(()) => true,
+    Err(e) => {
+      eprintln!("Warning, the following resource will not be available: {} because {:?}", vm.virtio_win_iso.display(), e);
+      false
     }
-
   }
 }
 
 
-async fn ensure_file_downloaded(url: &str, local_file: &std::path::Path) {
-  // use futures::StreamExt;
-
+// With a sha256 any failure is fatal, an unverified ISO must not be booted. Without one a failed
+// download is only reported, eg for an installed VM whose install ISO has since been deleted.
+async fn ensure_file_downloaded(url: &str, local_file: &std::path::Path, sha256: &str) -> Result<(), Box<dyn std::error::Error>> {
   if url.len() < 2 {
-    return;
+    return Ok(());
   }
 
-  let local_file_path = local_file.to_owned();
-  if let Some(local_parent_dir) = local_file_path.parent() {
-    println!("Ensuring {} exists...", local_parent_dir.display());
-    dump_error!( tokio::fs::create_dir_all(local_parent_dir).await );
+  let sha256 = if sha256.is_empty() { None } else { Some(sha256) };
+  if let Err(e) = download_file(url, local_file, sha256).await {
+    if sha256.is_some() {
+      return Err(e);
+    }
+    eprintln!("ERROR downloading {}: {}", url, e);
   }
-
-  dump_error!(
-    tokio::process::Command::new("wget")
-      .args(&["--continue", url, "-O", format!("{}", local_file.display()).as_str() ])
-      .status()
-      .await
-  );
-
-  // if ! local_file_path.exists() {
-  //   // Download it
-  //   println!("Downloading {} to {}", url, local_file.display());
-  //   let mut local_virtio_iso_file = tokio::fs::File::create(local_file).await.expect("Could not create file!");
-  //   let conn = reqwest::get(url).await.expect("Could not connect!");
-  //   let mut download_stream = conn.bytes_stream();
-
-  //   while let Some(item) = download_stream.next().await {
-  //     dump_error!( tokio::io::copy(&mut item.unwrap().as_ref(), &mut local_virtio_iso_file).await );
-  //   }
-
-  // }
+  Ok(())
 }
 
 
@@ -155,6 +230,14 @@ async fn do_shutdown() {
 
   // Allow spawned futures to complete...
   tokio::time::sleep( tokio::time::Duration::from_millis(400) ).await;
+
+  if let Ok(cleanup_files) = CLEANUP_FILES.lock() {
+    for cleanup_file in cleanup_files.iter().filter(|f| f.exists()) {
+      println!("Removing {}", cleanup_file.display());
+      dump_error!( std::fs::remove_file(cleanup_file) );
+    }
+  }
+
   println!("Goodbye!");
   std::process::exit(0);
 }
@@ -163,26 +246,27 @@ static QEMU_PROC_PID: once_cell::sync::Lazy<std::sync::atomic::AtomicI32> = once
   std::sync::atomic::AtomicI32::new( 0 )
 );
 
+// Temporary files (eg ephemeral overlays, the unattend ISO with its password) removed by do_shutdown() no matter how we exit
+static CLEANUP_FILES: once_cell::sync::Lazy<std::sync::Mutex<Vec<PathBuf>>> = once_cell::sync::Lazy::new(||
+  std::sync::Mutex::new( vec![] )
+);
 
-async fn vm_manager(mut path_to_config: String) {
 
+async fn find_config_file(path_to_config: &mut String) {
   if ! ( std::path::Path::new(&path_to_config).exists() && std::path::Path::new(&path_to_config).is_file() ) {
     // Scan under /j/bins/azure-contain/containers for a file containing this & use that
     let mut containers_dir_o = dump_error_and_ret!( tokio::fs::read_dir("/j/bins/azure-vm/vms").await );
     while let Some(container_toml) = dump_error_and_ret!( containers_dir_o.next_entry().await ) {
-      if container_toml.file_name().into_string().unwrap_or_default().contains(&path_to_config) {
-        path_to_config = container_toml.path().into_os_string().into_string().unwrap_or_default();
+      if container_toml.file_name().into_string().unwrap_or_default().contains(path_to_config.as_str()) {
+        *path_to_config = container_toml.path().into_os_string().into_string().unwrap_or_default();
         break;
       }
     }
   }
+}
 
-  let mut sys = sysinfo::System::new_all();
-  sys.refresh_all();
-  let sys_mem_mb = sys.total_memory() / (1024 * 1024);
-  println!("sys_mem_mb = {:?}", sys_mem_mb);
-
-  let _signal_task = tokio::spawn(handle_exit_signals());
+async fn load_vm_config(mut path_to_config: String) -> VMConfig {
+  find_config_file(&mut path_to_config).await;
 
   println!("Reading {}", &path_to_config);
   let vm_file_content = tokio::fs::read_to_string(path_to_config).await.expect("Could not read config file!");
@@ -190,14 +274,133 @@ async fn vm_manager(mut path_to_config: String) {
 
   vm_config.apply_env_overrides();
 
+  vm_config
+}
+
+// One-shot version of the REPL commands which make sense without an attached REPL
+async fn vm_command(mut path_to_config: String, args: Vec<String>) {
+  find_config_file(&mut path_to_config).await;
+  let vm_config = load_vm_config(path_to_config.clone()).await;
+  let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+  match args[0] {
+    "snapshot" => {
+      dump_error!( snapshot_command(&vm_config, &args[1..]).await );
+    }
+    "commit" => {
+      dump_error!( commit_command(&vm_config).await );
+    }
+    "disk" => {
+      dump_error!( disk_command(&vm_config, &args[1..]).await );
+    }
+    "backup" => {
+      dump_error!( backup_command(&vm_config, &args[1..]).await );
+    }
+    "hibernate" => {
+      dump_error!( hibernate_command(&vm_config).await );
+    }
+    "pause" => {
+      dump_error!( pause_command(&vm_config).await );
+    }
+    "rdp" => {
+      // Desktop launchers land here, so boot the VM if nobody has yet
+      dump_error!( ensure_vm_running(&vm_config, &path_to_config).await );
+      let words: Vec<String> = args[1..].iter().map(|a| a.to_string()).collect();
+      dump_error!( rdp_command(&vm_config, &words).await );
+    }
+    "apps" => {
+      apps_command(&vm_config);
+    }
+    "console" => {
+      dump_error!( console_command(&vm_config.vm).await );
+    }
+    "screenshot" => {
+      dump_error!( screenshot_command(&vm_config.vm, &args[1..]).await );
+    }
+    "sendkey" => {
+      dump_error!( sendkey_command(&vm_config.vm, args.get(1).unwrap_or(&"")).await );
+    }
+    "type" => {
+      dump_error!( type_command(&vm_config.vm, &args[1..].join(" ")).await );
+    }
+    "resume" => {
+      dump_error!( resume_command(&vm_config).await );
+    }
+    unknown => {
+      eprintln!("Unknown command {:?}", unknown);
+      dump_help();
+    }
+  }
+}
+
+async fn clone_vm(args: Vec<String>) {
+  let flatten = args.iter().any(|a| a == "--flatten");
+  let args: Vec<&String> = args.iter().filter(|a| *a != "--flatten").collect();
+  if args.len() != 2 {
+    return dump_help();
+  }
+
+  let mut src_config_path = args[0].clone();
+  find_config_file(&mut src_config_path).await;
+  println!("Reading {}", &src_config_path);
+
+  dump_error!( clone_command(std::path::Path::new(&src_config_path), args[1], flatten).await );
+}
+
+async fn serve_vm(args: Vec<String>) {
+  if args.len() != 1 {
+    return dump_help();
+  }
+
+  let mut config_path = args[0].clone();
+  find_config_file(&mut config_path).await;
+  let vm_config = load_vm_config(config_path.clone()).await;
+
+  dump_error!( serve_command(&vm_config, &config_path).await );
+}
+
+async fn export_launchers(args: Vec<String>) {
+  let icons = args.iter().any(|a| a == "--icons");
+  let args: Vec<&String> = args.iter().filter(|a| *a != "--icons").collect();
+  if args.len() != 1 {
+    return dump_help();
+  }
+
+  let mut config_path = args[0].clone();
+  find_config_file(&mut config_path).await;
+  let vm_config = load_vm_config(config_path.clone()).await;
+
+  dump_error!( export_launchers_command(&vm_config, std::path::Path::new(&config_path), icons).await );
+}
+
+// What woke the REPL up
+enum ReplEvent {
+  Line(std::io::Result<Option<String>>),
+  QemuExited(std::io::Result<std::process::ExitStatus>),
+  InstallDone(InstallDoneSignal),
+}
+
+async fn vm_manager(path_to_config: String, run_flags: Vec<String>) {
+  let mut sys = sysinfo::System::new_all();
+  sys.refresh_all();
+  let sys_mem_mb = sys.total_memory() / (1024 * 1024);
+  println!("sys_mem_mb = {:?}", sys_mem_mb);
+
+  let _signal_task = tokio::spawn(handle_exit_signals());
+
+  let mut vm_config = load_vm_config(path_to_config).await;
+  vm_config.apply_cli_flags(&run_flags);
   let vm_config = vm_config;
+  if vm_config.vm.serve_backend {
+    dump_error_and_ret!( vm_config.vm.check_serve_ports() );
+  }
 
   println!("vm_config={:?}", vm_config);
 
   let vm_is_physical_disk = vm_config.vm.disk_partuuid.len() > 1;
 
   if !vm_is_physical_disk {
-    ensure_file_downloaded(&vm_config.install.boot_iso_url, &vm_config.install.boot_iso).await;
+    dump_error_and_ret!( ensure_file_downloaded(&vm_config.install.boot_iso_url, &vm_config.install.boot_iso, &vm_config.install.boot_iso_sha256).await );
     dump_error!( filetime::set_file_mtime(&vm_config.install.boot_iso, filetime::FileTime::now()) );
   }
 
@@ -209,16 +412,22 @@ async fn vm_manager(mut path_to_config: String) {
     }
 
     // create a vm_config.vm.disk_image_gb sized image at vm_config.vm.disk_image
-    dump_error!(
-      tokio::process::Command::new("qemu-img")
-        .args(&["create", "-f", "qcow2", &vm_config.vm.disk_image.to_string_lossy(), format!("{}G", vm_config.vm.disk_image_gb).as_str() ])
-        .status()
-        .await
-    );
+    dump_error!( create_qcow2_image(&vm_config.vm.disk_image, vm_config.vm.disk_image_gb).await );
 
   }
+  else if !vm_is_physical_disk && vm_config.vm.disk_image_gb > 0 {
+    // disk_image_gb only applies on creation, point out when it has since been raised
+    if let Ok(Some(info)) = qemu_img_info(&vm_config.vm.disk_image).await.map(|chain| chain.into_iter().next()) {
+      if (vm_config.vm.disk_image_gb as u64) * 1024 * 1024 * 1024 > info.virtual_size {
+        println!("disk_image_gb = {} but {} is {}, run `disk resize root_disk {}G` to grow it",
+          vm_config.vm.disk_image_gb, vm_config.vm.disk_image.display(), human_size(info.virtual_size), vm_config.vm.disk_image_gb);
+      }
+    }
+  }
+
+  ensure_disks_exist(&vm_config.disks).await;
 
-  ensure_virtio_win_iso_exists().await;
+  let virtio_win_iso_available = ensure_virtio_win_iso_exists(&vm_config).await;
 
   // Spawn any require sub-processes the VM wants
   let mut preboot_children = vec![];
@@ -237,6 +446,22 @@ async fn vm_manager(mut path_to_config: String) {
   }
 
 
+  let mut root_disk_image = vm_config.vm.disk_image.clone();
+  if vm_config.vm.ephemeral {
+    if vm_is_physical_disk {
+      eprintln!("Ignoring ephemeral, it needs a qcow2 disk_image and this VM boots disk_partuuid {}", vm_config.vm.disk_partuuid);
+    }
+    else if vm_config.install.boot_iso.to_str().unwrap_or_default().len() > 1 && ! vm_config.vm.flag_path(".installed").exists() {
+      eprintln!("Ignoring ephemeral, the OS is not installed yet");
+    }
+    else {
+      root_disk_image = dump_error_and_ret!( create_ephemeral_overlay(&vm_config.vm).await );
+      if let Ok(mut cleanup_files) = CLEANUP_FILES.lock() {
+        cleanup_files.push(root_disk_image.clone());
+      }
+    }
+  }
+
   let vm_root_drive_arg: String;
   if vm_is_physical_disk {
     // Lookup disk holding vm_config.vm.disk_partuuid, and check if it exists.
@@ -264,446 +489,494 @@ async fn vm_manager(mut path_to_config: String) {
 
     // vm_root_drive_arg = format!("format=raw,file={},if=virtio", dev_reg_path.display() );
 
+    warn_if_unsafe_physical(vm_config.vm.disk_cache, &dev_reg_path);
+    let cache = vm_config.vm.disk_cache.as_str();
+    let aio = physical_disk_aio(vm_config.vm.disk_cache);
+
     // Edge case handling
     if vm_config.vm.root_disk_if_override.len() < 1 {
-      //vm_root_drive_arg = format!("id=root_disk,format=raw,cache=none,file={}", dev_reg_path.display() );
-      vm_root_drive_arg = format!("id=root_disk,format=raw,cache=unsafe,file={}", dev_reg_path.display() );
+      vm_root_drive_arg = format!("id=root_disk,format=raw,cache={},aio={},file={}", cache, aio, dev_reg_path.display() );
     }
     else {
       // Common case
-      //vm_root_drive_arg = format!("id=root_disk,format=raw,cache=none,file={},if={}", dev_reg_path.display(), vm_config.vm.root_disk_if_override );
-      vm_root_drive_arg = format!("id=root_disk,format=raw,cache=unsafe,file={},if={}", dev_reg_path.display(), vm_config.vm.root_disk_if_override );
+      vm_root_drive_arg = format!("id=root_disk,format=raw,cache={},aio={},file={},if={}", cache, aio, dev_reg_path.display(), vm_config.vm.root_disk_if_override );
     }
 
   }
   else {
-    //vm_root_drive_arg = format!("id=root_disk,format=qcow2,cache=none,file={}", vm_config.vm.disk_image.to_string_lossy() );
-    vm_root_drive_arg = format!("id=root_disk,format=qcow2,cache=unsafe,file={}", vm_config.vm.disk_image.to_string_lossy() );
+    vm_root_drive_arg = format!("id=root_disk,format=qcow2,cache={},file={}", vm_config.vm.disk_cache.as_str(), root_disk_image.to_string_lossy() );
   }
 
-  if vm_config.install.boot_iso.to_str().unwrap_or_default().len() > 1 {
-    // Check for install
-    let install_flag = vm_config.vm.flag_path(".installed");
+  let has_install_media = vm_config.install.boot_iso.to_str().unwrap_or_default().len() > 1;
+  let install_flag = vm_config.vm.flag_path(".installed");
+  if has_install_media {
     println!("install_flag = {:?}", install_flag);
+  }
 
-    if ! install_flag.exists() {
-      println!("");
-      println!("install_flag file {:?} does not exist, launching w/ install media connected.", install_flag);
-      println!("Please install the OS and then run: ");
-      println!("  touch {:?}", install_flag);
-      println!("");
-
-      let mut qemu_args: Vec<String> = vec![
-        "-bios".into(), vm_config.vm.bios_override.to_string(),
-        "-drive".into(), vm_root_drive_arg.to_string(),
-        "-enable-kvm".into(),
-        "-m".into(), format!("{}M", vm_config.vm.ram_mb ),
-        //"-cpu".into(), "host,hv_relaxed,hv_spinlocks=0x1fff,hv_vapic,hv_time".into(),
-        "-cpu".into(), vm_config.vm.cpu_override.to_string(),
-        "-smp".into(), "2".into(),
-        "-machine".into(), vm_config.vm.machine_override.to_string(),
-
-        // Use pulse API to talk to pipewire
-        //"-audiodev".into(), "id=pa,driver=pa,server=/run/user/1000/pulse/native".into(),
-        "-audiodev".into(), "id=alsa,driver=alsa".into(), // yay -S qemu-audio-alsa
-
-        // Hmmm... likely want more config in future.
-        "-nic".into(), "user,id=winnet0,id=mynet0,net=192.168.90.0/24,dhcpstart=192.168.90.10".into(),
-
-        // "-device".into(), "virtio-vga".into(), // gl=on,max_outputs=1 where do these get set ???
-        "-display".into(), "gtk".into(),
+  let spice_socket = vm_config.vm.flag_path(".spice.sock");
+  let qmp_socket = vm_config.vm.flag_path(".qmp.sock");
+  let qga_socket = vm_config.vm.flag_path(".qga.sock");
+  let serial_socket = serial_socket_path(&vm_config.vm);
 
-        // Attach boot ISO
-        "-drive".into(), format!("file={},if=ide,index=1,media=cdrom,readonly=on", vm_config.install.boot_iso.display() ),
+  println!("Spice socket file = {}", spice_socket.display() );
+  println!("QMP socket file = {}", qmp_socket.display() );
+  println!("Guest agent socket file = {}", qga_socket.display() );
+  if !serial_in_addtl_args(&vm_config.vm) {
+    println!("Serial console socket file = {}, log = {}", serial_socket.display(), serial_log_path(&vm_config.vm).display() );
+  }
 
-        // Attach drivers
-        // "-drive".into(), format!("file={},if=ide,index=2,media=cdrom", VIRTIO_WIN_ISO_LOCAL_PATH ),
+  let mut input_lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+  let mut stdin_open = true;
 
-        "-boot".into(), "d".into(), // c == first hd, d == first cd-rom drive
+  // The install phase and the regular run share everything but boot media and boot order;
+  // when the install is detected as finished we come back around with the run configuration.
+  if vm_config.vm.screenshot_every_secs > 0 {
+    // Outlives the install relaunch, the QMP socket path stays the same
+    tokio::spawn(screenshot_task(qmp_socket.clone(), vm_config.vm.flag_path(".screendump"), vm_config.vm.flag_path(".thumbnails"), vm_config.vm.screenshot_every_secs));
+  }
 
-        //"-boot".into(), "menu=on,splash-time=18".into(),
+  tokio::spawn(serial_log_rotate_task(serial_log_path(&vm_config.vm)));
 
-      ];
+  let display_args = dump_error_and_ret!( display_qemu_args(&vm_config.vm, &spice_socket) );
 
-      { // Magic stuff
-        if vm_config.vm.bios_override.len() < 1 {
-          qemu_args.drain(0..2); // Remove "-bios", "" b/c empty string sent in
-        }
+  let mut qemu_proc = 'launch: loop {
+    let installing = has_install_media && ! install_flag.exists();
 
-        // If "-boot" appears in addtl_args, remove the LAST two arguments
-        if vm_config.vm.addtl_args.iter().any(|e| e == "-boot" ) {
-          qemu_args.drain(qemu_args.len()-2..qemu_args.len()); // Remove "-boot", "" b/c empty string sent in
+    let mut unattend_iso = None;
+    if installing {
+      if let Some(unattend) = &vm_config.unattend {
+        match build_unattend_media(&vm_config, unattend).await {
+          Ok(iso) => {
+            if let Ok(mut cleanup_files) = CLEANUP_FILES.lock() {
+              cleanup_files.push(iso.clone());
+            }
+            unattend_iso = Some(iso);
+          }
+          Err(e) => eprintln!("Cannot build autounattend.xml media, falling back to a manual install: {}", e),
         }
       }
 
-      qemu_args.extend(vm_config.vm.addtl_args);
-      let qemu_args = qemu_args;
-
-      // If we request > 1/2 system RAM, limit to just the first 1/2 minus 1gb.
-      let sys_mem_limit_mb = (sys_mem_mb/2) - 1024;
-      if vm_config.vm.ram_mb <= sys_mem_limit_mb as usize {
-        let debug_qemu_args = qemu_args.join(" ");
-        println!(">>>");
-        println!(">>> qemu-system-x86_64 {}", debug_qemu_args);
-        println!(">>>");
-
-        dump_error!(
-          tokio::process::Command::new("qemu-system-x86_64")
-            .args(&qemu_args)
-            .status()
-            .await
-        );
+      println!("");
+      println!("install_flag file {:?} does not exist, launching w/ install media connected.", install_flag);
+      if unattend_iso.is_some() {
+        println!("Windows Setup runs unattended from autounattend.xml; press a key if the firmware asks to boot from CD.");
+      }
+      if vm_config.install.done_when != InstallDoneSignal::Manual {
+        println!("The install counts as finished once {:?} answers; the VM then restarts into its normal configuration.", install_done_signals(&vm_config));
+        println!("To mark it finished yourself, shut the VM down and run: ");
       }
       else {
-        // Throw inside systemd-run and limit real ram to 8000mb
-
-        let mut systemd_run_args: Vec<String> = vec![];
-
-        systemd_run_args.push("--scope".to_string());
-
-        systemd_run_args.push("-p".to_string());
-        systemd_run_args.push(format!("MemoryHigh={}M", sys_mem_limit_mb));
-
-        systemd_run_args.push("-p".to_string());
-        systemd_run_args.push("MemorySwapMax=999G".to_string());
-
-        systemd_run_args.push("--user".to_string());
-        systemd_run_args.push("qemu-system-x86_64".to_string());
-
-        systemd_run_args.extend(qemu_args);
-
-        let debug_systemd_run_args = systemd_run_args.join(" ");
-        println!(">>>");
-        println!(">>> systemd-run {}", debug_systemd_run_args);
-        println!(">>>");
-
-        dump_error!(
-          tokio::process::Command::new("systemd-run")
-            .args(&systemd_run_args)
-            .status()
-            .await
-        );
-
+        println!("Please install the OS and then run: ");
       }
-      return;
+      println!("  touch {:?}", install_flag);
+      println!("Type `gui` to watch the install.");
+      println!("");
     }
-  }
 
-  // Now run the regular VM
-
-  let spice_socket = vm_config.vm.flag_path(".spice.sock");
-  let qmp_socket = vm_config.vm.flag_path(".qmp.sock");
-
-  println!("Spice socket file = {}", spice_socket.display() );
-  println!("QMP socket file = {}", qmp_socket.display() );
-
-  if qmp_socket.exists() {
-    dump_error!( tokio::fs::remove_file(&qmp_socket).await );
-  }
-
-  let mut qemu_args: Vec<String> = vec![
-    "-bios".into(), (&vm_config.vm.bios_override).into(), // "-bios" MUST always be in this position, b/c we remove these if bios_override.len() < 1
+    for socket in [&qmp_socket, &qga_socket, &serial_socket] {
+      if socket.exists() {
+        dump_error!( tokio::fs::remove_file(socket).await );
+      }
+    }
+    dump_error!( rotate_serial_log(&serial_log_path(&vm_config.vm)).await );
 
-    "-drive".into(), vm_root_drive_arg,      // No matter qcow2 or raw disk, id=root_disk
+    // IDE indexes this launch attaches itself; [[disks]] with bus = "ide" get the rest
+    let mut ide_in_use = vec![];
+    if !vm_root_drive_arg.contains(",if=") || vm_root_drive_arg.ends_with(",if=ide") {
+      ide_in_use.push(ROOT_DISK_IDE_INDEX);
+    }
 
-    "-enable-kvm".into(),
-    "-m".into(), format!("{}M", vm_config.vm.ram_mb ),
-    //"-cpu".into(), "host,hv_relaxed,hv_spinlocks=0x1fff,hv_vapic,hv_time".into(),
-    "-cpu".into(), vm_config.vm.cpu_override.into(),
-  ];
-  if vm_config.vm.smp_override.len() > 0 {
-    qemu_args.append(&mut vec![
-      "-smp".into(),
-      vm_config.vm.smp_override.into(),
-    ]);
-  }
+    let mut qemu_args: Vec<String> = vec![
+      "-bios".into(), (&vm_config.vm.bios_override).into(), // "-bios" MUST always be in this position, b/c we remove these if bios_override.len() < 1
+
+      "-drive".into(), vm_root_drive_arg.clone(),      // No matter qcow2 or raw disk, id=root_disk
+
+      "-enable-kvm".into(),
+      "-m".into(), format!("{}M", vm_config.vm.ram_mb ),
+      //"-cpu".into(), "host,hv_relaxed,hv_spinlocks=0x1fff,hv_vapic,hv_time".into(),
+      "-cpu".into(), vm_config.vm.cpu_override.to_string(),
+    ];
+    if vm_config.vm.smp_override.len() > 0 {
+      qemu_args.append(&mut vec![
+        "-smp".into(),
+        vm_config.vm.smp_override.to_string(),
+      ]);
+    }
 
-  if vm_config.vm.usb_passthrough_devices.len() > 0 {
-    // For each device, un-bind it and add it to the VM arguments (IF the device is plugged in; if not plugged in, do nothing)
-    for usb_passthrough_vendor_product in vm_config.vm.usb_passthrough_devices.iter() {
-      match process_usb_passthrough_to_qemu_args(usb_passthrough_vendor_product) {
-        Ok(addtl_qemu_args) => {
-          qemu_args.extend(addtl_qemu_args);
-        }
-        Err(e) => {
-          eprintln!("[ usb_passthrough_devices {} ] {:?}", usb_passthrough_vendor_product, e);
+    if vm_config.vm.usb_passthrough_devices.len() > 0 {
+      // For each device, un-bind it and add it to the VM arguments (IF the device is plugged in; if not plugged in, do nothing)
+      for usb_passthrough_vendor_product in vm_config.vm.usb_passthrough_devices.iter() {
+        match process_usb_passthrough_to_qemu_args(usb_passthrough_vendor_product) {
+          Ok(addtl_qemu_args) => {
+            qemu_args.extend(addtl_qemu_args);
+          }
+          Err(e) => {
+            eprintln!("[ usb_passthrough_devices {} ] {:?}", usb_passthrough_vendor_product, e);
+          }
         }
       }
     }
-  }
-
-  qemu_args.append(&mut vec![
-    "-machine".into(), vm_config.vm.machine_override.to_string(),
-
-    "-qmp".into(), format!("unix:{},server=on,wait=off", qmp_socket.display() ),
 
-    // Use pulse API to talk to pipewire
-    //"-audiodev".into(), "id=pa,driver=pa,server=/run/user/1000/pulse/native".into(),
-    "-audiodev".into(), "id=alsa,driver=alsa".into(), // yay -S qemu-audio-alsa
-    "-device".into(), "intel-hda".into(), "-device".into(), "hda-output,audiodev=alsa".into(), // frontend HW presented to VM
-
-    // Hmmm... likely want more config in future.
-    "-nic".into(), "user,id=winnet0,id=mynet0,net=192.168.90.0/24,dhcpstart=192.168.90.10,hostfwd=tcp:127.0.0.1:3389-:3389,hostfwd=udp:127.0.0.1:3389-:3389,hostfwd=tcp:127.0.0.1:2222-:22".into(),
+    qemu_args.append(&mut vec![
+      "-machine".into(), vm_config.vm.machine_override.to_string(),
 
-    // Assume guest drivers are installed during install phase, use spice UI
+      "-qmp".into(), format!("unix:{},server=on,wait=off", qmp_socket.display() ),
 
-    "-spice".into(), // /dev/dri/by-path/pci-0000:00:02.0-render is the intel GPU
-      format!("unix=on,addr={},gl={},disable-ticketing=on", spice_socket.display(), vm_config.vm.spice_gl_override),
-      //format!("unix=on,addr={},gl={},rendernode={},disable-ticketing=on", spice_socket.display(), vm_config.vm.spice_gl_override, vm_config.vm.spice_rendernode_override ),
+      // Use pulse API to talk to pipewire
+      //"-audiodev".into(), "id=pa,driver=pa,server=/run/user/1000/pulse/native".into(),
+      "-audiodev".into(), "id=alsa,driver=alsa".into(), // yay -S qemu-audio-alsa
+      "-device".into(), "intel-hda".into(), "-device".into(), "hda-output,audiodev=alsa".into(), // frontend HW presented to VM
 
-    "-device".into(), "virtio-serial-pci".into(),
-    "-device".into(), "virtserialport,chardev=spicechannel0,name=com.redhat.spice.0".into(),
-    "-chardev".into(), "spicevmc,id=spicechannel0,name=vdagent".into(),
+      // Hmmm... likely want more config in future.
+      "-nic".into(), format!("user,id=winnet0,id=mynet0,net=192.168.90.0/24,dhcpstart=192.168.90.10,hostfwd=tcp:127.0.0.1:{rdp}-:3389,hostfwd=udp:127.0.0.1:{rdp}-:3389,hostfwd=tcp:127.0.0.1:{ssh}-:22",
+        rdp=vm_config.vm.host_port(3389), ssh=vm_config.vm.host_port(2222)),
 
-    // "-vga".into(), "virtio".into(), // alternatively; -vga std?
+      "-device".into(), "virtio-serial-pci".into(),
 
-    // "-drive".into(), format!("file={},if=ide,index=2,media=cdrom", VIRTIO_WIN_ISO_LOCAL_PATH ),
+      // QEMU guest agent, used to tell "still booting" from "booted", to detect a finished install and for REPL guest commands
+      "-chardev".into(), format!("socket,path={},server=on,wait=off,id=qga0", qga_socket.display()),
+      "-device".into(), "virtserialport,chardev=qga0,name=org.qemu.guest_agent.0".into(),
 
-    "-boot".into(), "c".into(), // c == first hd, d == first cd-rom drive
+    ]);
+    qemu_args.extend(display_args.iter().cloned());
+    qemu_args.extend(serial_console_args(&vm_config.vm));
+
+    if installing {
+      // Attach boot ISO
+      qemu_args.extend(ide_cdrom_args(&vm_config.vm.machine_override, INSTALL_ISO_IDE_INDEX, &vm_config.install.boot_iso, INSTALL_MEDIA_DRIVE_IDS[0]));
+      ide_in_use.push(INSTALL_ISO_IDE_INDEX);
+      qemu_args.append(&mut vec![
+        "-boot".into(), "d".into(), // c == first hd, d == first cd-rom drive
+        //"-boot".into(), "menu=on,splash-time=18".into(),
+      ]);
+    }
+    else {
+      qemu_args.append(&mut vec![
+        "-boot".into(), "c".into(), // c == first hd, d == first cd-rom drive
+      ]);
+    }
 
-  ]);
+    { // Magic stuff
+      if vm_config.vm.bios_override.len() < 1 {
+        qemu_args.drain(0..2); // Remove "-bios", "" b/c empty string sent in
+      }
 
-  { // Magic stuff
-    if vm_config.vm.bios_override.len() < 1 {
-      qemu_args.drain(0..2); // Remove "-bios", "" b/c empty string sent in
+      // If "-boot" appears in addtl_args, remove the LAST two arguments
+      if vm_config.vm.addtl_args.iter().any(|e| e == "-boot" ) {
+        qemu_args.drain(qemu_args.len()-2..qemu_args.len()); // Remove "-boot", "" b/c empty string sent in
+      }
     }
 
-    // If "-boot" appears in addtl_args, remove the LAST two arguments
-    if vm_config.vm.addtl_args.iter().any(|e| e == "-boot" ) {
-      qemu_args.drain(qemu_args.len()-2..qemu_args.len()); // Remove "-boot", "" b/c empty string sent in
+    let attach_virtio_win_iso = virtio_win_iso_available && if installing { vm_config.install_needs_virtio_win_iso() } else { vm_config.vm.mount_windows_virtio_iso_at_runtime };
+    if attach_virtio_win_iso {
+      // Drivers; setup's "Load driver" finds them under D:\ or E:\
+      qemu_args.extend(ide_cdrom_args(&vm_config.vm.machine_override, VIRTIO_WIN_ISO_IDE_INDEX, &vm_config.vm.virtio_win_iso, VIRTIO_WIN_ISO_DRIVE_ID));
+      ide_in_use.push(VIRTIO_WIN_ISO_IDE_INDEX);
     }
-  }
-
-  qemu_args.extend(vm_config.vm.addtl_args);
-  let qemu_args = qemu_args;
-
-  // If we request > 1/2 system RAM, limit to just the first 1/2 minus 1gb.
-  let sys_mem_limit_mb = (sys_mem_mb/2) - 1024;
-  let mut qemu_proc = if vm_config.vm.ram_mb <= sys_mem_limit_mb as usize {
-    let debug_qemu_args = qemu_args.join(" ");
-    println!(">>>");
-    println!(">>> qemu-system-x86_64 {}", debug_qemu_args);
-    println!(">>>");
-
-    if vm_config.vm.drop_to_serial {
-      // Run interactively & return an "empty" process
-      let r = tokio::process::Command::new("qemu-system-x86_64")
-            .args(&qemu_args)
-            .stdin(std::process::Stdio::inherit())
-            .stdout(std::process::Stdio::inherit())
-            .stderr(std::process::Stdio::inherit())
-            .spawn()
-            .expect("Could not spawn child proc")
-            .wait().await;
-      if let Err(e) = r {
-        eprintln!("{:?}", e);
+    if installing {
+      if let Some(unattend_iso) = &unattend_iso {
+        qemu_args.extend(ide_cdrom_args(&vm_config.vm.machine_override, SEED_ISO_IDE_INDEX, unattend_iso, INSTALL_MEDIA_DRIVE_IDS[1]));
+        ide_in_use.push(SEED_ISO_IDE_INDEX);
       }
-
-      tokio::process::Command::new("echo")
-            .args(&["Done"])
-            .spawn()
-            .expect("Could not spawn child proc")
     }
-    else {
-      tokio::process::Command::new("qemu-system-x86_64")
-            .args(&qemu_args)
-            .spawn()
-            .expect("Could not spawn child proc")
+    else if let Some(cloud_init) = &vm_config.cloud_init {
+      match build_cloud_init_seed(&vm_config, cloud_init).await {
+        Ok(seed_iso) => {
+          qemu_args.extend(ide_cdrom_args(&vm_config.vm.machine_override, SEED_ISO_IDE_INDEX, &seed_iso, CLOUD_INIT_SEED_DRIVE_ID));
+          ide_in_use.push(SEED_ISO_IDE_INDEX);
+        }
+        Err(e) => eprintln!("Cannot build the cloud-init seed, booting without it: {}", e),
+      }
     }
-  }
-  else {
-    // Throw inside systemd-run and limit real ram to sys_mem_limit_mb
-
-    let mut systemd_run_args: Vec<String> = vec![];
-
-    systemd_run_args.push("--scope".to_string());
-
-    systemd_run_args.push("-p".to_string());
-    systemd_run_args.push(format!("MemoryHigh={}M", sys_mem_limit_mb));
-
-    systemd_run_args.push("-p".to_string());
-    systemd_run_args.push("MemorySwapMax=999G".to_string());
 
-    systemd_run_args.push("--user".to_string());
-    systemd_run_args.push("qemu-system-x86_64".to_string());
+    qemu_args.extend(dump_error_and_ret!( disks_to_qemu_args(&vm_config.disks, &vm_config.vm.machine_override, &ide_in_use) ));
+    qemu_args.extend(vm_config.vm.addtl_qemu_args());
 
-    systemd_run_args.extend(qemu_args);
-
-    let debug_systemd_run_args = systemd_run_args.join(" ");
-    println!(">>>");
-    println!(">>> systemd-run {}", debug_systemd_run_args);
-    println!(">>>");
+    let mut resuming = false;
+    if !installing {
+      // The hardware fingerprint for hibernate is taken before -incoming gets added
+      record_qemu_args(&vm_config.vm, &qemu_args).await;
+      let hibernate_resume_args = resume_args(&vm_config.vm, &qemu_args).await;
+      resuming = !hibernate_resume_args.is_empty();
+      qemu_args.extend(hibernate_resume_args);
+    }
+    let qemu_args = qemu_args;
+    let forwarded_ports = hostfwd_ports(&qemu_args);
+
+    // If we request > 1/2 system RAM, limit to just the first 1/2 minus 1gb.
+    let sys_mem_limit_mb = (sys_mem_mb/2) - 1024;
+    let mut qemu_proc = if vm_config.vm.ram_mb <= sys_mem_limit_mb as usize {
+      let debug_qemu_args = qemu_args.join(" ");
+      println!(">>>");
+      println!(">>> qemu-system-x86_64 {}", debug_qemu_args);
+      println!(">>>");
+
+      if vm_config.vm.drop_to_serial && serial_in_addtl_args(&vm_config.vm) {
+        // addtl_args put the serial port on QEMU's stdio: run interactively & return an "empty" process
+        let r = tokio::process::Command::new("qemu-system-x86_64")
+              .args(&qemu_args)
+              .stdin(std::process::Stdio::inherit())
+              .stdout(std::process::Stdio::inherit())
+              .stderr(std::process::Stdio::inherit())
+              .spawn()
+              .expect("Could not spawn child proc")
+              .wait().await;
+        if let Err(e) = r {
+          eprintln!("{:?}", e);
+        }
 
-    // Attempt to swapon another 16gb of ram iff it exists on /mnt/scratch/
-    for swap_n in 1..4 {
-      if std::path::Path::new("/mnt/scratch/swap-files").exists() {
-        dump_error!(
-          tokio::process::Command::new("sudo")
-            .args(&[
-              "swapon",
-              format!("/mnt/scratch/swap-files/swap-{}", swap_n).as_str(),
-            ])
-            .status()
-            .await
-        );
+        tokio::process::Command::new("echo")
+              .args(["Done"])
+              .spawn()
+              .expect("Could not spawn child proc")
       }
-      if std::path::Path::new("/mnt/azure-data").exists() {
-        dump_error!(
-          tokio::process::Command::new("sudo")
-            .args(&[
-              "swapon",
-              format!("/mnt/azure-data/swap-files/swap-{}", swap_n).as_str(),
-            ])
-            .status()
-            .await
-        );
+      else {
+        tokio::process::Command::new("qemu-system-x86_64")
+              .args(&qemu_args)
+              .stdin(qemu_stdin(&vm_config.vm))
+              .spawn()
+              .expect("Could not spawn child proc")
       }
     }
+    else {
+      // Throw inside systemd-run and limit real ram to sys_mem_limit_mb
 
-    tokio::process::Command::new("systemd-run")
-        .args(&systemd_run_args)
-        .spawn()
-        .expect("Could not spawn child proc")
-  };
-
-  let qemu_pid = qemu_proc.id().unwrap_or(0);
-  QEMU_PROC_PID.store(qemu_pid as i32, std::sync::atomic::Ordering::SeqCst);
-
-  tokio::time::sleep(tokio::time::Duration::from_millis(1200)).await;
-
-  let mut input_lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
-
-  print!("> "); // prompt
-  dump_error!( std::io::stdout().flush() );
-  dump_error!( tokio::io::stdout().flush().await );
+      let mut systemd_run_args: Vec<String> = vec![];
 
-  let qapi_stream = dump_error_and_ret!( qapi::futures::QgaStreamTokio::open_uds(qmp_socket).await );
-  let (qga, _handle) = qapi_stream.spawn_tokio();
+      systemd_run_args.push("--scope".to_string());
 
-  let _sync_value = &qga as *const _ as usize as i32;
-  //dump_error!( qga.guest_sync(sync_value).await ); // TODO re-investigate setting this up
+      systemd_run_args.push("-p".to_string());
+      systemd_run_args.push(format!("MemoryHigh={}M", sys_mem_limit_mb));
 
+      systemd_run_args.push("-p".to_string());
+      systemd_run_args.push("MemorySwapMax=999G".to_string());
 
-  while let Ok(Some(line)) = input_lines.next_line().await {
+      systemd_run_args.push("--user".to_string());
+      systemd_run_args.push("qemu-system-x86_64".to_string());
 
-    match qemu_proc.try_wait() {
-      Ok(Some(exit_code)) => { println!("Qemu exited with {}, exiting!", exit_code ); break; },
-      _ => { /* don't care */ }
-    }
+      systemd_run_args.extend(qemu_args);
 
-    let line = line.trim();
+      let debug_systemd_run_args = systemd_run_args.join(" ");
+      println!(">>>");
+      println!(">>> systemd-run {}", debug_systemd_run_args);
+      println!(">>>");
 
-    if line.len() > 0 {
-      if line == "gui" {
-        println!("Launching SPICE client...");
-        dump_error!(
-          tokio::process::Command::new("spicy")
-            .args(&[
-              format!("--uri=spice+unix://{}", spice_socket.display()).as_str()
-            ])
-            .status()
-            .await
-        );
+      // Attempt to swapon another 16gb of ram iff it exists on /mnt/scratch/
+      for swap_n in 1..4 {
+        if std::path::Path::new("/mnt/scratch/swap-files").exists() {
+          dump_error!(
+            tokio::process::Command::new("sudo")
+              .args(&[
+                "swapon",
+                format!("/mnt/scratch/swap-files/swap-{}", swap_n).as_str(),
+              ])
+              .status()
+              .await
+          );
+        }
+        if std::path::Path::new("/mnt/azure-data").exists() {
+          dump_error!(
+            tokio::process::Command::new("sudo")
+              .args(&[
+                "swapon",
+                format!("/mnt/azure-data/swap-files/swap-{}", swap_n).as_str(),
+              ])
+              .status()
+              .await
+          );
+        }
       }
-      else if line.starts_with("rdp") {
 
-        let rdp_pw_to_censor = read_secret(&vm_config.vm.rdp_pass).await;
-
-        let mut rdp_args: Vec<String> = vec![];
+      tokio::process::Command::new("systemd-run")
+          .args(&systemd_run_args)
+          .stdin(qemu_stdin(&vm_config.vm))
+          .spawn()
+          .expect("Could not spawn child proc")
+    };
 
-        rdp_args.push("/cert:ignore".to_string());
+    let qemu_pid = qemu_proc.id().unwrap_or(0);
+    QEMU_PROC_PID.store(qemu_pid as i32, std::sync::atomic::Ordering::SeqCst);
 
-        rdp_args.push("/w:1920".to_string());
-        rdp_args.push("/h:1080".to_string());
+    tokio::time::sleep(tokio::time::Duration::from_millis(1200)).await;
 
-        rdp_args.push("/drive:DOWNLOADS,/j/downloads".to_string());
-        rdp_args.push("/dynamic-resolution".to_string());
+    if let Err(e) = set_vnc_password(&vm_config.vm, &qmp_socket).await {
+      eprintln!();
+      eprintln!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+      eprintln!("!!! {}", e);
+      eprintln!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+      eprintln!();
+    }
 
-        if vm_config.vm.rdp_uname.len() > 0 {
-          rdp_args.push( format!("/u:{}", vm_config.vm.rdp_uname) );
-        }
-        if vm_config.vm.rdp_pass.len() > 0 {
-          rdp_args.push( format!("/p:{}", read_secret(&vm_config.vm.rdp_pass).await ) );
-        }
-        rdp_args.push("/v:127.0.0.1".to_string());
+    if resuming {
+      dump_error!( finish_resume(&vm_config.vm).await );
+    }
 
-        // Audio config - we want to send the aduio over the RDP connection
-        rdp_args.push("/audio-mode:0".to_string());
-        rdp_args.push("/microphone:sys:alsa".to_string());
+    // Pausing Setup because nobody is watching it would stall the install
+    if !installing && vm_config.vm.idle_suspend_minutes > 0 {
+      tokio::spawn(idle_suspend_task(qmp_socket.clone(), forwarded_ports, spice_socket.clone(), vm_config.vm.idle_suspend_minutes));
+    }
 
-        rdp_args.push("/auto-reconnect-max-retries:64".to_string());
+    if vm_config.vm.drop_to_serial && !serial_in_addtl_args(&vm_config.vm) {
+      dump_error!( attach_console_at_boot(&vm_config.vm).await );
+    }
 
+    let install_done = async {
+      if installing { wait_for_install_done(&vm_config).await } else { std::future::pending().await }
+    };
+    tokio::pin!(install_done);
+    let mut install_pending = installing;
 
-        if let Some(cmd_exe) = line.split(" ").nth(1) {
-          println!("cmd_exe = {:?}", cmd_exe);
-          rdp_args.push(format!("/app:{}", cmd_exe));
+    print!("> "); // prompt
+    dump_error!( std::io::stdout().flush() );
+    dump_error!( tokio::io::stdout().flush().await );
 
-          // Append up to 20 args
-          let mut cmd_arg_args = cmd_exe.to_string();
-          for i in 2..20 {
-            if let Some(cmd_arg) = line.split(" ").nth(i) { // ... Why did I do that? This is so dumb.
-              // println!("cmd_arg = {:?}", cmd_arg);
-              cmd_arg_args += &(" ".to_string() + cmd_arg);
+    loop {
+      let event = tokio::select! {
+        line = input_lines.next_line(), if stdin_open => ReplEvent::Line(line),
+        exit_status = qemu_proc.wait() => ReplEvent::QemuExited(exit_status),
+        done_signal = &mut install_done, if install_pending => ReplEvent::InstallDone(done_signal),
+      };
+      let line = match event {
+        ReplEvent::Line(Ok(Some(line))) => line,
+        ReplEvent::Line(_) => {
+          // This adds a capability for background vms w/o interactive STDIN to remain running until something else pokes them
+          // set AZURE_VM_HOLD_WHEN_STDIN_STOPS=t to use
+          match std::env::var("AZURE_VM_HOLD_WHEN_STDIN_STOPS") {
+            Ok(val) if val.contains("1") || val.contains("t") || val.contains("T") => {
+              println!("STDIN has stopped or errored but we are holding the VM pid={} open because AZURE_VM_HOLD_WHEN_STDIN_STOPS={}", qemu_pid, val);
+              stdin_open = false;
+              continue;
             }
+            _ => break 'launch qemu_proc,
           }
-          if cmd_arg_args.len() > 0 {
-            rdp_args.push(format!("/app-cmd:{}", cmd_arg_args));
+        }
+        ReplEvent::QemuExited(exit_status) => {
+          match exit_status {
+            Ok(exit_code) => println!("Qemu exited with {}, exiting!", exit_code ),
+            Err(e) => eprintln!("Cannot wait for qemu: {:?}", e),
           }
+          break 'launch qemu_proc;
         }
+        ReplEvent::InstallDone(done_signal) => {
+          println!();
+          println!("Install finished ({:?} answered), writing {:?}", done_signal, install_flag);
+          install_pending = false;
+          dump_error!( tokio::fs::write(&install_flag, b"").await );
+          // Relaunching while the install QEMU may still hold the root disk and sockets would start a second one
+          if let Err(e) = finish_install(&qmp_socket, &mut qemu_proc).await {
+            eprintln!("ERROR finishing the install: {}", e);
+            eprintln!("Staying in the REPL; shut the VM down and start it again to boot the normal run configuration.");
+            print!("> "); // prompt
+            dump_error!( std::io::stdout().flush() );
+            continue;
+          }
+          // Ejected and QEMU is gone, so nothing needs autounattend.xml and its plain text password anymore
+          if let Some(unattend_iso) = unattend_iso.as_ref().filter(|iso| iso.exists()) {
+            dump_error!( tokio::fs::remove_file(unattend_iso).await );
+          }
+          println!("Restarting into the normal run configuration...");
+          continue 'launch;
+        }
+      };
 
-        rdp_args.extend(vm_config.vm.addtl_rdp_args.clone());
-
-        let rdp_args = rdp_args;
+      let line = line.trim();
 
-        let freerdp_bin = match std::env::var("WAYLAND_DISPLAY") {
-          Ok(waland_disp_val) => {
-            if waland_disp_val.len() > 0 {
-              "wlfreerdp"
-            }
-            else {
-              "xfreerdp"
-            }
+      if line.len() > 0 {
+        if line == "gui" {
+          println!("Launching {:?} viewer...", vm_config.vm.display);
+          dump_error!( gui_command(&vm_config.vm, &spice_socket).await );
+        }
+        else if line == "apps" {
+          apps_command(&vm_config);
+        }
+        else if line == "console" {
+          dump_error!( console_command(&vm_config.vm).await );
+        }
+        else if line == "screenshot" || line.starts_with("screenshot ") {
+          let words: Vec<&str> = line.split_whitespace().collect();
+          dump_error!( screenshot_command(&vm_config.vm, &words[1..]).await );
+        }
+        else if line == "sendkey" || line.starts_with("sendkey ") {
+          dump_error!( sendkey_command(&vm_config.vm, line["sendkey".len()..].trim()).await );
+        }
+        else if let Some(text) = line.strip_prefix("type ") {
+          match shell_split(text) {
+            Ok(words) => dump_error!( type_command(&vm_config.vm, &words.join(" ")).await ),
+            Err(e) => eprintln!("{}", e),
           }
-          Err(_) => {
-            "xfreerdp"
+        }
+        else if line == "rdp" || line.starts_with("rdp ") {
+          match shell_split(&line[3..]) {
+            Ok(words) => dump_error!( rdp_command(&vm_config, &words).await ),
+            Err(e) => eprintln!("{}", e),
           }
-        };
-
-        {
-          let debug_rdp_args = rdp_args.iter().map(|arg| {
-            if arg.contains(&rdp_pw_to_censor) {
-              "*******".to_string()
-            }
-            else {
-              arg.to_string()
-            }
-          }).collect::<Vec<_>>().join(" ");
-          println!("{} {}", freerdp_bin, debug_rdp_args );
         }
-
-        dump_error!(
-          tokio::process::Command::new(freerdp_bin)
-            .args(&rdp_args)
-            .status()
-            .await
-        );
-      }
-      else if line.starts_with("cmd") {
-        let sh_cmd = &line[4..];
-        eprintln!("Running command: {}", &sh_cmd);
-        dump_error!(
-          tokio::process::Command::new("sh")
-            .args(&["-c", &sh_cmd])
-            .status()
-            .await
-        );
-      }
-      else if line == "help" || line == "h" {
-        println!(r#"Commands:
+        else if line == "snapshot" || line.starts_with("snapshot ") {
+          let words: Vec<&str> = line.split_whitespace().collect();
+          dump_error!( snapshot_command(&vm_config, &words[1..]).await );
+        }
+        else if line == "disk" || line.starts_with("disk ") {
+          let words: Vec<&str> = line.split_whitespace().collect();
+          dump_error!( disk_command(&vm_config, &words[1..]).await );
+        }
+        else if line == "backup" || line.starts_with("backup ") {
+          let words: Vec<&str> = line.split_whitespace().collect();
+          dump_error!( backup_command(&vm_config, &words[1..]).await );
+        }
+        else if line == "hibernate" {
+          match hibernate_command(&vm_config).await {
+            Ok(()) => break 'launch qemu_proc,
+            Err(e) => eprintln!("ERROR {}:{}> {:?}", file!(), line!(), e),
+          }
+        }
+        else if line == "commit" {
+          dump_error!( commit_command(&vm_config).await );
+        }
+        else if line == "pause" {
+          dump_error!( pause_command(&vm_config).await );
+        }
+        else if line == "resume" {
+          dump_error!( resume_command(&vm_config).await );
+        }
+        else if line.starts_with("cmd") {
+          let sh_cmd = &line[4..];
+          eprintln!("Running command: {}", &sh_cmd);
+          dump_error!(
+            tokio::process::Command::new("sh")
+              .args(&["-c", &sh_cmd])
+              .status()
+              .await
+          );
+        }
+        else if line == "help" || line == "h" {
+          println!(r#"Commands:
 - gui
-    Opens SPICE client
-- rdp
-    Opens RDP client to 127.0.0.1:3389
+    Opens the viewer for the display mode (spicy / remote-viewer / vncviewer)
+- rdp [APP|PROGRAM] [ARGS...]
+    Opens RDP client to 127.0.0.1:3389 once the guest accepts RDP (waits up to rdp_wait_timeout_secs).
+    APP is an [[apps]] name, anything else a program path in the guest; quote args with spaces
+- apps
+    Lists the [[apps]] RemoteApp catalog
+- console
+    Attaches this terminal to the guest's serial port, Ctrl-] detaches back to this REPL
+- screenshot [FILE]
+    Saves the guest's screen as a PNG, by default ./NAME-UNIXTIME.png
+- sendkey KEY[-KEY...]
+    Presses QEMU key names together, eg sendkey ctrl-alt-delete
+- type "TEXT"
+    Types TEXT on a US keyboard layout, \n presses enter
+- snapshot create|list|revert|delete [NAME]
+    Manage qcow2 snapshots of the root disk and qcow2 [[disks]]
+- hibernate
+    Save the VM's RAM next to its disk and exit; the next run resumes where it left off
+- commit
+    Merge the --ephemeral overlay back into disk_image
+- pause | resume
+    Stop/continue the guest's vCPUs; RAM stays allocated but no CPU is used while paused
+- disk resize NAME SIZE [--shrink] | disk compact [NAME] | disk convert NAME FORMAT DEST | disk check [NAME] [--repair] | disk info [NAME]
+    Disk maintenance; NAME is root_disk (default) or a [[disks]] id, SIZE like 200G or +20G
+- backup full | backup incremental | backup list | backup restore POINT NEW.qcow2
+    Live backups of root_disk into backup_dir, tracked with a persistent dirty bitmap
 - cmd COMMAND
     runs given shell command (eg toggle-aoc or similar) on the Host
 - help
@@ -713,47 +986,40 @@ async fn vm_manager(mut path_to_config: String) {
 - *
     Run as command in VM, returning output.
 "#);
-      }
-      else if line == "quit" || line == "exit" {
-        break;
-      }
-      else {
-        // Connect to QMP and send line in verbatim
-        println!("Sending to QMP: {}", line);
-
-        match qga.execute(qapi::qga::guest_info { }).await {
-          Ok(info) => {
-            println!("Guest Agent version: {}", info.version);
-          }
-          Err(e) => {
-            println!("Error: {:?}", e);
+        }
+        else if line == "quit" || line == "exit" {
+          break 'launch qemu_proc;
+        }
+        else {
+          // Connect to the guest agent and send line in verbatim
+          println!("Sending to guest agent: {}", line);
+
+          match qapi::futures::QgaStreamTokio::open_uds(&qga_socket).await {
+            Ok(qapi_stream) => {
+              let (qga, _handle) = qapi_stream.spawn_tokio();
+              match qga.execute(qapi::qga::guest_info { }).await {
+                Ok(info) => {
+                  println!("Guest Agent version: {}", info.version);
+                }
+                Err(e) => {
+                  println!("Error: {:?}", e);
+                }
+              }
+            }
+            Err(e) => {
+              println!("Error: {:?}", e);
+            }
           }
+
         }
 
       }
 
+      print!("> "); // prompt
+      dump_error!( std::io::stdout().flush() );
+      dump_error!( tokio::io::stdout().flush().await );
     }
-
-    print!("> "); // prompt
-    dump_error!( std::io::stdout().flush() );
-    dump_error!( tokio::io::stdout().flush().await );
-  }
-
-  // This adds a capability for background vms w/o interactive STDIN to remain running until something else pokes them
-  // set AZURE_VM_HOLD_WHEN_STDIN_STOPS=t to use
-  if let Ok(val) = std::env::var("AZURE_VM_HOLD_WHEN_STDIN_STOPS") {
-    if val.contains("1") || val.contains("t") || val.contains("T") {
-      println!("STDIN has stopped or errored but we are holding the VM pid={} open because AZURE_VM_HOLD_WHEN_STDIN_STOPS={}", qemu_pid, val);
-      tokio::time::sleep(tokio::time::Duration::from_millis(1200)).await; // Juuuust in case qemu hasn't even booted yet -_-
-      loop {
-        match qemu_proc.try_wait() {
-          Ok(Some(exit_code)) => { println!("Qemu exited with {}, exiting!", exit_code ); break; },
-          _ => { /* don't care */ }
-        }
-        tokio::time::sleep(tokio::time::Duration::from_millis(1200)).await;
-      }
-    }
-  }
+  };
 
   println!("");
   dump_error!( std::io::stdout().flush() );
@@ -802,20 +1068,3 @@ async fn vm_manager(mut path_to_config: String) {
 
 }
 
-async fn read_secret(secret_config_value: &str) -> String {
-  // Is it stored in a file?
-  match tokio::fs::read(secret_config_value).await {
-    Ok(file_val) => String::from_utf8_lossy(&file_val).as_ref().trim().to_string(),
-    Err(_e) => {
-      // Is it an environment variable?
-      match std::env::var(secret_config_value) {
-        Ok(env_var_val) => env_var_val.trim().to_string(),
-        Err(_e2) => {
-          // Use value as-is
-          secret_config_value.to_string()
-        }
-      }
-    }
-  }
-}
-
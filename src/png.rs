@@ -0,0 +1,397 @@
+// Just enough PNG to save QEMU's PPM screendumps without an image crate: 8-bit RGB, one IDAT,
+// and a single fixed-Huffman deflate block. Rows use the Sub filter, which turns the flat areas
+// of a desktop or installer screen into runs of zeros that the LZ77 matcher collapses.
+
+// Deflate length codes 257..=285 and distance codes 0..=29: (base, extra bits)
+const LENGTH_CODES: [(u16, u8); 29] = [
+  (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0), (11, 1), (13, 1), (15, 1), (17, 1),
+  (19, 2), (23, 2), (27, 2), (31, 2), (35, 3), (43, 3), (51, 3), (59, 3), (67, 4), (83, 4), (99, 4), (115, 4),
+  (131, 5), (163, 5), (195, 5), (227, 5), (258, 0),
+];
+const DISTANCE_CODES: [(u16, u8); 30] = [
+  (1, 0), (2, 0), (3, 0), (4, 0), (5, 1), (7, 1), (9, 2), (13, 2), (17, 3), (25, 3), (33, 4), (49, 4),
+  (65, 5), (97, 5), (129, 6), (193, 6), (257, 7), (385, 7), (513, 8), (769, 8), (1025, 9), (1537, 9),
+  (2049, 10), (3073, 10), (4097, 11), (6145, 11), (8193, 12), (12289, 12), (16385, 13), (24577, 13),
+];
+
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+
+/// An RGB image, 3 bytes per pixel, rows top to bottom
+pub struct RgbImage {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<u8>,
+}
+
+/// Parses a binary (P6) PPM with 8-bit channels, the format of QEMU's screendump
+pub fn parse_ppm(data: &[u8]) -> Result<RgbImage, String> {
+  let mut fields = vec![];
+  let mut pos = 0;
+  while fields.len() < 4 {
+    while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+      if data[pos] == b'#' {
+        while pos < data.len() && data[pos] != b'\n' {
+          pos += 1;
+        }
+      }
+      pos += 1;
+    }
+    let start = pos;
+    while pos < data.len() && !data[pos].is_ascii_whitespace() {
+      pos += 1;
+    }
+    if start == pos {
+      return Err("PPM header is truncated".into());
+    }
+    fields.push(String::from_utf8_lossy(&data[start..pos]).to_string());
+  }
+  pos += 1; // Exactly one whitespace byte separates the header from the pixels
+
+  if fields[0] != "P6" {
+    return Err(format!("Expected a binary P6 PPM, got {:?}", fields[0]));
+  }
+  let width: usize = fields[1].parse().map_err(|_| format!("Bad PPM width {:?}", fields[1]))?;
+  let height: usize = fields[2].parse().map_err(|_| format!("Bad PPM height {:?}", fields[2]))?;
+  if fields[3] != "255" {
+    return Err(format!("Only 8-bit PPMs are supported, maxval is {}", fields[3]));
+  }
+  let end = width.checked_mul(height).and_then(|n| n.checked_mul(3)).and_then(|n| n.checked_add(pos))
+    .ok_or_else(|| format!("PPM size {}x{} is too large", width, height))?;
+  let pixels = data.get(pos..end).ok_or("PPM pixel data is truncated")?;
+  Ok(RgbImage { width, height, pixels: pixels.to_vec() })
+}
+
+/// Shrinks the image to at most max_width pixels wide by averaging square blocks
+pub fn downscale(image: &RgbImage, max_width: usize) -> RgbImage {
+  let factor = image.width.div_ceil(max_width.max(1)).max(1);
+  if factor == 1 || image.width == 0 || image.height == 0 {
+    return RgbImage { width: image.width, height: image.height, pixels: image.pixels.clone() };
+  }
+  // Never below 1x1; blocks that run past the edge of a thin image repeat its last row or column
+  let width = (image.width / factor).max(1);
+  let height = (image.height / factor).max(1);
+  let mut pixels = Vec::with_capacity(width * height * 3);
+  for y in 0..height {
+    for x in 0..width {
+      for channel in 0..3 {
+        let mut sum = 0usize;
+        for dy in 0..factor {
+          let row = (y * factor + dy).min(image.height - 1) * image.width;
+          for dx in 0..factor {
+            let column = (x * factor + dx).min(image.width - 1);
+            sum += image.pixels[(row + column) * 3 + channel] as usize;
+          }
+        }
+        pixels.push((sum / (factor * factor)) as u8);
+      }
+    }
+  }
+  RgbImage { width, height, pixels }
+}
+
+/// Encodes the image as a PNG file
+pub fn encode_png(image: &RgbImage) -> Vec<u8> {
+  let stride = image.width * 3;
+  let mut filtered = Vec::with_capacity((stride + 1) * image.height);
+  for row in image.pixels.chunks(stride.max(1)).take(image.height) {
+    filtered.push(1); // Sub: each byte minus the same channel of the pixel to its left
+    for (i, byte) in row.iter().enumerate() {
+      let left = if i >= 3 { row[i - 3] } else { 0 };
+      filtered.push(byte.wrapping_sub(left));
+    }
+  }
+
+  let mut ihdr = vec![];
+  ihdr.extend_from_slice(&(image.width as u32).to_be_bytes());
+  ihdr.extend_from_slice(&(image.height as u32).to_be_bytes());
+  ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits, truecolor, deflate, adaptive filtering, no interlace
+
+  let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+  write_chunk(&mut png, b"IHDR", &ihdr);
+  write_chunk(&mut png, b"IDAT", &zlib_compress(&filtered));
+  write_chunk(&mut png, b"IEND", &[]);
+  png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  let start = png.len();
+  png.extend_from_slice(kind);
+  png.extend_from_slice(data);
+  let crc = crc32(&png[start..]);
+  png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = 0xffff_ffffu32;
+  for byte in data.iter() {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+    }
+  }
+  !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for chunk in data.chunks(5552) { // Largest run that cannot overflow before the modulo
+    for byte in chunk.iter() {
+      a += *byte as u32;
+      b += a;
+    }
+    a %= 65521;
+    b %= 65521;
+  }
+  (b << 16) | a
+}
+
+// Deflate emits bits least significant first; Huffman codes go most significant first
+struct BitWriter {
+  out: Vec<u8>,
+  bits: u64,
+  count: u32,
+}
+
+impl BitWriter {
+  fn write(&mut self, value: u32, count: u32) {
+    self.bits |= (value as u64) << self.count;
+    self.count += count;
+    while self.count >= 8 {
+      self.out.push(self.bits as u8);
+      self.bits >>= 8;
+      self.count -= 8;
+    }
+  }
+
+  fn write_code(&mut self, code: u32, len: u32) {
+    self.write(code.reverse_bits() >> (32 - len), len);
+  }
+
+  fn write_literal(&mut self, symbol: u16) {
+    match symbol {
+      0..=143 => self.write_code(0x30 + symbol as u32, 8),
+      144..=255 => self.write_code(0x190 + (symbol as u32 - 144), 9),
+      256..=279 => self.write_code(symbol as u32 - 256, 7),
+      _ => self.write_code(0xc0 + (symbol as u32 - 280), 8),
+    }
+  }
+
+  fn write_match(&mut self, len: usize, distance: usize) {
+    let code = LENGTH_CODES.iter().rposition(|(base, _)| *base as usize <= len).unwrap_or(0);
+    let (base, extra) = LENGTH_CODES[code];
+    self.write_literal(257 + code as u16);
+    self.write((len - base as usize) as u32, extra as u32);
+
+    let code = DISTANCE_CODES.iter().rposition(|(base, _)| *base as usize <= distance).unwrap_or(0);
+    let (base, extra) = DISTANCE_CODES[code];
+    self.write_code(code as u32, 5);
+    self.write((distance - base as usize) as u32, extra as u32);
+  }
+
+  fn finish(mut self) -> Vec<u8> {
+    if self.count > 0 {
+      self.out.push(self.bits as u8);
+    }
+    self.out
+  }
+}
+
+fn zlib_compress(data: &[u8]) -> Vec<u8> {
+  let mut writer = BitWriter { out: vec![0x78, 0x01], bits: 0, count: 0 };
+  writer.write(1, 1); // Final block
+  writer.write(1, 2); // Fixed Huffman codes
+
+  // Most recent position of each 3-byte prefix; one probe per position is plenty for screenshots
+  let mut last_seen = vec![usize::MAX; 1 << HASH_BITS];
+  let hash = |at: usize| {
+    let key = (data[at] as u32) << 16 | (data[at + 1] as u32) << 8 | data[at + 2] as u32;
+    (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+  };
+
+  let mut pos = 0;
+  while pos < data.len() {
+    let mut match_len = 0;
+    let mut match_distance = 0;
+    if pos + MIN_MATCH <= data.len() {
+      let slot = hash(pos);
+      let candidate = last_seen[slot];
+      last_seen[slot] = pos;
+      if candidate != usize::MAX && pos - candidate <= WINDOW {
+        let max_len = MAX_MATCH.min(data.len() - pos);
+        while match_len < max_len && data[candidate + match_len] == data[pos + match_len] {
+          match_len += 1;
+        }
+        match_distance = pos - candidate;
+      }
+    }
+
+    if match_len >= MIN_MATCH {
+      writer.write_match(match_len, match_distance);
+      // Index the skipped positions too so later runs can refer back into this one
+      for skipped in pos + 1..(pos + match_len).min(data.len().saturating_sub(MIN_MATCH - 1)) {
+        last_seen[hash(skipped)] = skipped;
+      }
+      pos += match_len;
+    }
+    else {
+      writer.write_literal(data[pos] as u16);
+      pos += 1;
+    }
+  }
+  writer.write_literal(256); // End of block
+
+  let mut out = writer.finish();
+  out.extend_from_slice(&adler32(data).to_be_bytes());
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Just enough inflate to read back zlib_compress's single fixed-Huffman block
+  struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+  }
+
+  impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> u32 {
+      let mut value = 0;
+      for i in 0..count {
+        let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+        value |= (bit as u32) << i;
+        self.pos += 1;
+      }
+      value
+    }
+
+    fn code(&mut self, len: u32) -> u32 {
+      (0..len).fold(0, |code, _| code << 1 | self.bits(1))
+    }
+
+    fn literal(&mut self) -> u16 {
+      let code = self.code(7);
+      if code <= 0x17 {
+        return 256 + code as u16;
+      }
+      let code = code << 1 | self.bits(1);
+      match code {
+        0x30..=0xbf => (code - 0x30) as u16,
+        0xc0..=0xc7 => 280 + (code - 0xc0) as u16,
+        _ => 144 + ((code << 1 | self.bits(1)) - 0x190) as u16,
+      }
+    }
+  }
+
+  fn zlib_decompress(zlib: &[u8]) -> Vec<u8> {
+    assert_eq!(&zlib[..2], &[0x78, 0x01]);
+    let mut reader = BitReader { data: &zlib[2..zlib.len() - 4], pos: 0 };
+    assert_eq!(reader.bits(1), 1, "single final block");
+    assert_eq!(reader.bits(2), 1, "fixed Huffman codes");
+    let mut out: Vec<u8> = vec![];
+    loop {
+      let symbol = reader.literal();
+      match symbol {
+        0..=255 => out.push(symbol as u8),
+        256 => break,
+        _ => {
+          let (base, extra) = LENGTH_CODES[symbol as usize - 257];
+          let len = base as usize + reader.bits(extra as u32) as usize;
+          let (base, extra) = DISTANCE_CODES[reader.code(5) as usize];
+          let distance = base as usize + reader.bits(extra as u32) as usize;
+          for _ in 0..len {
+            out.push(out[out.len() - distance]);
+          }
+        }
+      }
+    }
+    let trailer = u32::from_be_bytes(zlib[zlib.len() - 4..].try_into().unwrap());
+    assert_eq!(trailer, adler32(&out));
+    out
+  }
+
+  // Splits a PNG into (kind, data) chunks, checking each CRC on the way
+  fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    let mut chunks = vec![];
+    let mut pos = 8;
+    while pos < png.len() {
+      let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+      let kind_and_data = &png[pos + 4..pos + 8 + len];
+      let crc = u32::from_be_bytes(png[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+      assert_eq!(crc, crc32(kind_and_data));
+      chunks.push((kind_and_data[..4].try_into().unwrap(), kind_and_data[4..].to_vec()));
+      pos += 12 + len;
+    }
+    chunks
+  }
+
+  #[test]
+  fn crc32_and_adler32_known_values() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+  }
+
+  #[test]
+  fn encode_png_round_trip() {
+    // 2 pixels of noise then a flat run long enough for the matcher to use
+    let width = 40;
+    let mut pixels = vec![255, 0, 0, 1, 2, 3];
+    pixels.resize(width * 2 * 3, 0x80);
+    let image = RgbImage { width, height: 2, pixels: pixels.clone() };
+    let png = encode_png(&image);
+
+    let chunks = chunks(&png);
+    let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+    assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+    assert_eq!(chunks[0].1, [0, 0, 0, 40, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+    assert!(chunks[2].1.is_empty());
+    assert!(chunks[1].1.len() < pixels.len(), "flat area should compress");
+
+    // Undo the Sub filter and compare with what went in
+    let filtered = zlib_decompress(&chunks[1].1);
+    let stride = width * 3;
+    assert_eq!(filtered.len(), (stride + 1) * 2);
+    let mut decoded = vec![];
+    for row in filtered.chunks(stride + 1) {
+      assert_eq!(row[0], 1);
+      let start = decoded.len();
+      for (i, byte) in row[1..].iter().enumerate() {
+        let left = if i >= 3 { decoded[start + i - 3] } else { 0 };
+        decoded.push(byte.wrapping_add(left));
+      }
+    }
+    assert_eq!(decoded, pixels);
+  }
+
+  #[test]
+  fn parse_ppm_reads_header_and_pixels() {
+    let image = parse_ppm(b"P6\n# QEMU screendump\n2 1\n255\n\x01\x02\x03\x04\x05\x06").unwrap();
+    assert_eq!((image.width, image.height), (2, 1));
+    assert_eq!(image.pixels, [1, 2, 3, 4, 5, 6]);
+
+    assert!(parse_ppm(b"P6\n2 1\n255\n\x01\x02").is_err());
+    assert!(parse_ppm(b"P3\n1 1\n255\n\x01\x02\x03").is_err());
+    assert!(parse_ppm(b"P6\n1 1\n65535\n\x01\x02\x03\x04\x05\x06").is_err());
+    assert!(parse_ppm(format!("P6\n{} {}\n255\n", usize::MAX, usize::MAX).as_bytes()).is_err());
+  }
+
+  #[test]
+  fn downscale_averages_and_never_goes_below_one_pixel() {
+    let image = RgbImage { width: 4, height: 2, pixels: [[0, 10, 20], [100, 110, 120]].repeat(4).concat() };
+    let half = downscale(&image, 2);
+    assert_eq!((half.width, half.height), (2, 1));
+    assert_eq!(half.pixels, [50, 60, 70, 50, 60, 70]);
+
+    let thin = RgbImage { width: 8, height: 1, pixels: vec![200; 8 * 3] };
+    let tiny = downscale(&thin, 2);
+    assert_eq!((tiny.width, tiny.height), (2, 1));
+    assert_eq!(tiny.pixels, [200; 6]);
+  }
+}
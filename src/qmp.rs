@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use qapi::qmp;
+use serde::{Serialize, Deserialize};
+
+pub type QmpHandle = qapi::futures::QapiService<qapi::futures::QmpStreamTokio<tokio::io::WriteHalf<tokio::net::UnixStream>>>;
+
+/// Returns a QMP connection if the VM is running, None if nothing is listening on the socket.
+/// QEMU only serves one client per socket, so hold these for a single command and then drop them.
+pub async fn qmp_connect_if_running(qmp_socket: &Path) -> Result<Option<QmpHandle>, Box<dyn std::error::Error>> {
+  match tokio::net::UnixStream::connect(qmp_socket).await {
+    Ok(socket) => Ok(Some(qmp_negotiate(qmp_socket, socket).await?)),
+    // Missing or stale socket left behind by a previous run
+    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused || e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
+async fn qmp_negotiate(qmp_socket: &Path, socket: tokio::net::UnixStream) -> Result<QmpHandle, Box<dyn std::error::Error>> {
+  // A busy socket accepts the connection but never sends a greeting, so don't wait forever
+  let negotiation = tokio::time::timeout(
+    tokio::time::Duration::from_secs(3),
+    qapi::futures::QmpStreamTokio::open(socket)
+  ).await.map_err(|_| format!("Timed out waiting for a QMP greeting on {}, is another client connected?", qmp_socket.display()))??;
+  let qapi_stream = negotiation.negotiate().await?;
+  let (qmp, _handle) = qapi_stream.spawn_tokio();
+  Ok(qmp)
+}
+
+/// Waits for a QMP job to conclude, dismisses it, and surfaces its error if it failed.
+/// Jobs which pause in the ready state (active commits, mirrors) are completed automatically.
+pub async fn qmp_wait_for_job(qmp: &QmpHandle, job_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+  let mut sent_complete = false;
+  loop {
+    let jobs = qmp.execute(qmp::query_jobs { }).await?;
+    match jobs.iter().find(|job| job.id == job_id) {
+      Some(job) if job.status == qmp::JobStatus::concluded => {
+        let job_error = job.error.clone();
+        qmp.execute(qmp::job_dismiss { id: job_id.to_string() }).await?;
+        return match job_error {
+          Some(e) => Err(format!("Job {} failed: {}", job_id, e).into()),
+          None => Ok(()),
+        };
+      }
+      Some(job) if job.status == qmp::JobStatus::ready && !sent_complete => {
+        qmp.execute(qmp::job_complete { id: job_id.to_string() }).await?;
+        sent_complete = true;
+      }
+      Some(job) => {
+        if job.total_progress > 0 {
+          print!("\r{} {:?} {}%   ", job_id, job.status, (job.current_progress * 100) / job.total_progress);
+          dump_error!( std::io::Write::flush(&mut std::io::stdout()) );
+        }
+      }
+      None => return Ok(()), // Auto-dismissed jobs vanish once they are done
+    }
+    tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+  }
+}
+
+/// -drive id=root_disk gets an auto-generated node name, look it up so jobs can target it
+pub async fn qmp_node_name_of_device(qmp: &QmpHandle, device: &str) -> Result<String, Box<dyn std::error::Error>> {
+  let block_devs = qmp.execute(qmp::query_block { }).await?;
+  block_devs.iter()
+    .find(|dev| dev.device == device)
+    .and_then(|dev| dev.inserted.as_ref())
+    .and_then(|inserted| inserted.node_name.clone())
+    .ok_or_else(|| format!("No block node is attached to device {}", device).into())
+}
+
+/// query-status with only the fields we need; the generated StatusInfo requires
+/// `singlestep`, which newer QEMU releases no longer send.
+#[derive(Debug, Serialize)]
+pub struct QueryStatus { }
+
+#[derive(Debug, Deserialize)]
+pub struct VmStatus {
+  pub running: bool,
+  pub status: String,
+}
+
+impl qapi::Command for QueryStatus {
+  type Ok = VmStatus;
+  const NAME: &'static str = "query-status";
+  const ALLOW_OOB: bool = false;
+}
+impl qmp::QmpCommand for QueryStatus { }
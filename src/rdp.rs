@@ -0,0 +1,279 @@
+use tokio::io::AsyncWriteExt;
+
+use crate::structs::*;
+use crate::guest_ready::*;
+use crate::secrets::*;
+
+/// rdp [APP|PROGRAM] [ARGS...]
+/// APP is the name of an [[apps]] entry, anything else is taken as a program path inside the guest.
+/// Without arguments this opens the full desktop.
+pub async fn rdp_command(vm_config: &VMConfig, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+  if !wait_for_rdp_ready(&vm_config.vm).await {
+    return Err("Guest is not accepting RDP connections".into());
+  }
+
+  let rdp_pass = if vm_config.vm.rdp_pass.is_empty() { String::new() } else { read_secret(&vm_config.vm.rdp_pass).await? };
+
+  let rdp = &vm_config.rdp;
+  let mut rdp_args: Vec<String> = vec![];
+
+  rdp_args.push(match rdp.cert {
+    RdpCertPolicy::Ignore => "/cert:ignore".to_string(),
+    RdpCertPolicy::Tofu => "/cert:tofu".to_string(),
+  });
+
+  if rdp.multimon {
+    rdp_args.push("/multimon".to_string());
+  }
+  else if rdp.resolution == "fullscreen" {
+    rdp_args.push("/f".to_string());
+  }
+  else {
+    let (width, height) = rdp.resolution.split_once('x').ok_or(format!("Bad [rdp] resolution {:?}, expected WIDTHxHEIGHT or fullscreen", rdp.resolution))?;
+    rdp_args.push(format!("/w:{}", width));
+    rdp_args.push(format!("/h:{}", height));
+  }
+  if rdp.dynamic_resolution {
+    rdp_args.push("/dynamic-resolution".to_string());
+  }
+  if rdp.scale_percent > 0 {
+    rdp_args.push(format!("/scale-desktop:{}", rdp.scale_percent));
+  }
+
+  for drive in rdp.shared_drives.iter() {
+    rdp_args.push(format!("/drive:{}", drive));
+  }
+  rdp_args.push(if rdp.clipboard { "+clipboard".to_string() } else { "-clipboard".to_string() });
+
+  if !vm_config.vm.rdp_uname.is_empty() {
+    rdp_args.push( format!("/u:{}", vm_config.vm.rdp_uname) );
+  }
+  // The password goes through FreeRDP's stdin, /p: would show it to everyone in /proc/*/cmdline.
+  // FreeRDP prompts for whatever is missing in the order username, domain, password.
+  let mut credentials_stdin = String::new();
+  if !rdp_pass.is_empty() {
+    rdp_args.push("/from-stdin:force".to_string());
+    if vm_config.vm.rdp_uname.is_empty() {
+      credentials_stdin.push('\n');
+    }
+    if !vm_config.vm.rdp_uname.contains(['\\', '@']) {
+      rdp_args.push("/d:".to_string()); // No domain, and no domain prompt either
+    }
+    credentials_stdin.push_str(&rdp_pass);
+    credentials_stdin.push('\n');
+  }
+  rdp_args.push(format!("/v:127.0.0.1:{}", vm_config.vm.host_port(3389)));
+  if !rdp.gateway.is_empty() {
+    rdp_args.push(format!("/gateway:{}", rdp.gateway));
+  }
+
+  // Audio config - we want to send the aduio over the RDP connection
+  rdp_args.push(if rdp.audio { "/audio-mode:0".to_string() } else { "/audio-mode:2".to_string() });
+  if !rdp.microphone.is_empty() {
+    rdp_args.push(format!("/microphone:{}", rdp.microphone));
+  }
+
+  rdp_args.push("/auto-reconnect-max-retries:64".to_string());
+
+  let freerdp_bin = match (rdp.client.as_str(), std::env::var("WAYLAND_DISPLAY")) {
+    ("auto", Ok(waland_disp_val)) if !waland_disp_val.is_empty() => "wlfreerdp",
+    ("auto", _) => "xfreerdp",
+    (client, _) => client,
+  };
+
+  if let Some((first, rest)) = args.split_first() {
+    let app = match vm_config.apps.iter().find(|app| &app.name == first) {
+      Some(app) => app.clone(),
+      None => VMApp { name: first.clone(), program: first.clone(), working_dir: String::new(), args: vec![], rdp_args: vec![] },
+    };
+    println!("Launching RemoteApp {} ({})", app.name, app.program);
+    let app_args: Vec<String> = app.args.iter().chain(rest.iter()).cloned().collect();
+    rdp_args.extend(remote_app_args(is_freerdp3(freerdp_bin), &app.program, &app_args, &app.working_dir)?);
+    rdp_args.extend(app.rdp_args);
+  }
+
+  // Last so they can override anything above
+  rdp_args.extend(vm_config.vm.addtl_rdp_args.clone());
+
+  println!("{} {}", freerdp_bin, rdp_args.join(" "));
+
+  let mut freerdp = tokio::process::Command::new(freerdp_bin)
+    .args(&rdp_args)
+    .stdin(std::process::Stdio::piped())
+    .spawn()?;
+  if let Some(mut stdin) = freerdp.stdin.take() {
+    stdin.write_all(credentials_stdin.as_bytes()).await?;
+    // Dropping stdin closes it, so FreeRDP never waits on a prompt we did not expect
+  }
+  freerdp.wait().await?;
+  Ok(())
+}
+
+// FreeRDP 3 ships sdl-freerdp and renames the others to xfreerdp3 / wlfreerdp3
+fn is_freerdp3(freerdp_bin: &str) -> bool {
+  let bin_name = freerdp_bin.rsplit('/').next().unwrap_or(freerdp_bin);
+  bin_name.starts_with("sdl-freerdp") || bin_name.ends_with("freerdp3")
+}
+
+/// FreeRDP 2 takes /app:, /app-cmd: and /app-workdir:, FreeRDP 3 folds them into one
+/// /app:program:..,cmd:..,workdir:.. option
+fn remote_app_args(freerdp3: bool, program: &str, app_args: &[String], working_dir: &str) -> Result<Vec<String>, String> {
+  let cmd = windows_command_line(app_args);
+  if !freerdp3 {
+    let mut args = vec![format!("/app:{}", program)];
+    if !app_args.is_empty() {
+      args.push(format!("/app-cmd:{}", cmd));
+    }
+    if !working_dir.is_empty() {
+      args.push(format!("/app-workdir:{}", working_dir));
+    }
+    return Ok(args);
+  }
+
+  // FreeRDP 3 splits the option on every comma and has no way to escape one
+  for (key, value) in [("program", program), ("cmd", cmd.as_str()), ("workdir", working_dir)] {
+    if value.contains(',') {
+      return Err(format!("FreeRDP 3 cannot pass a comma in the RemoteApp {}: {:?}", key, value));
+    }
+  }
+  let mut app_opt = format!("/app:program:{}", program);
+  if !app_args.is_empty() {
+    app_opt.push_str(&format!(",cmd:{}", cmd));
+  }
+  if !working_dir.is_empty() {
+    app_opt.push_str(&format!(",workdir:{}", working_dir));
+  }
+  Ok(vec![app_opt])
+}
+
+/// apps: list the [[apps]] catalog
+pub fn apps_command(vm_config: &VMConfig) {
+  if vm_config.apps.is_empty() {
+    println!("No [[apps]] configured for {}", vm_config.vm.name);
+  }
+  for app in vm_config.apps.iter() {
+    println!("  {:<16} {}{}", app.name, app.program, if app.args.is_empty() { String::new() } else { format!(" {}", windows_command_line(&app.args)) });
+  }
+}
+
+/// Splits a REPL line into words with shell-style quoting: 'single quotes' are literal,
+/// "double quotes" allow \" and \\, and a backslash outside quotes only escapes whitespace,
+/// quotes and backslashes so Windows paths like C:\Windows\notepad.exe need no quoting.
+pub fn shell_split(line: &str) -> Result<Vec<String>, String> {
+  let mut words = vec![];
+  let mut word = String::new();
+  let mut in_word = false;
+  let mut chars = line.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match c {
+      '\'' => {
+        in_word = true;
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => word.push(c),
+            None => return Err(format!("Unterminated ' in {:?}", line)),
+          }
+        }
+      }
+      '"' => {
+        in_word = true;
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') if matches!(chars.peek(), Some('"') | Some('\\')) => word.push(chars.next().unwrap_or_default()),
+            Some(c) => word.push(c),
+            None => return Err(format!("Unterminated \" in {:?}", line)),
+          }
+        }
+      }
+      '\\' if matches!(chars.peek(), Some(next) if next.is_whitespace() || *next == '\'' || *next == '"' || *next == '\\') => {
+        in_word = true;
+        word.push(chars.next().unwrap_or_default());
+      }
+      c if c.is_whitespace() => {
+        if in_word {
+          words.push(std::mem::take(&mut word));
+          in_word = false;
+        }
+      }
+      c => {
+        in_word = true;
+        word.push(c);
+      }
+    }
+  }
+  if in_word {
+    words.push(word);
+  }
+  Ok(words)
+}
+
+/// Joins args into a Windows command line (CommandLineToArgvW rules) for /app-cmd
+fn windows_command_line(args: &[String]) -> String {
+  args.iter().map(|arg| {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+      return arg.clone();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+      if c == '\\' {
+        backslashes += 1;
+        continue;
+      }
+      // Backslashes only escape when they precede a quote
+      let escaped_backslashes = if c == '"' { backslashes * 2 + 1 } else { backslashes };
+      quoted.push_str(&"\\".repeat(escaped_backslashes));
+      quoted.push(c);
+      backslashes = 0;
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+  }).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn strings(words: &[&str]) -> Vec<String> {
+    words.iter().map(|w| w.to_string()).collect()
+  }
+
+  #[test]
+  fn shell_split_quoting() {
+    assert_eq!(shell_split("excel 'my book.xlsx'").unwrap(), strings(&["excel", "my book.xlsx"]));
+    assert_eq!(shell_split(r#"notepad "say \"hi\"" """#).unwrap(), strings(&["notepad", r#"say "hi""#, ""]));
+    assert_eq!(shell_split(r"C:\Windows\notepad.exe a\ b").unwrap(), strings(&[r"C:\Windows\notepad.exe", "a b"]));
+    assert!(shell_split("notepad 'unterminated").is_err());
+    assert!(shell_split("notepad \"unterminated").is_err());
+  }
+
+  #[test]
+  fn windows_command_line_quoting() {
+    assert_eq!(windows_command_line(&strings(&["/s", "C:\\Program Files\\x"])), r#"/s "C:\Program Files\x""#);
+    assert_eq!(windows_command_line(&strings(&["", r#"a"b"#])), r#""" "a\"b""#);
+    // Trailing backslashes are doubled so they do not escape the closing quote
+    assert_eq!(windows_command_line(&strings(&["C:\\my dir\\"])), r#""C:\my dir\\""#);
+  }
+
+  #[test]
+  fn remote_app_args_freerdp2() {
+    assert_eq!(remote_app_args(false, "notepad.exe", &strings(&["a b"]), "C:\\Users").unwrap(),
+      strings(&["/app:notepad.exe", "/app-cmd:\"a b\"", "/app-workdir:C:\\Users"]));
+    assert_eq!(remote_app_args(false, "notepad.exe", &[], "").unwrap(), strings(&["/app:notepad.exe"]));
+  }
+
+  #[test]
+  fn remote_app_args_freerdp3() {
+    assert_eq!(remote_app_args(true, "notepad.exe", &strings(&["x.txt"]), "C:\\Users").unwrap(),
+      strings(&["/app:program:notepad.exe,cmd:x.txt,workdir:C:\\Users"]));
+    assert_eq!(remote_app_args(true, "notepad.exe", &[], "").unwrap(), strings(&["/app:program:notepad.exe"]));
+    assert!(remote_app_args(true, "notepad.exe", &strings(&["a,b"]), "").is_err());
+    assert!(remote_app_args(true, "C:\\a,b\\x.exe", &[], "").is_err());
+    assert!(remote_app_args(false, "notepad.exe", &strings(&["a,b"]), "").is_ok());
+  }
+}
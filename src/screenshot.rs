@@ -0,0 +1,99 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::qmp::*;
+use crate::png::*;
+
+// Width of the --screenshot-every thumbnails
+const THUMBNAIL_WIDTH: usize = 480;
+// The series is capped to the newest thumbnails; at one a minute this is the last 2 hours
+const THUMBNAILS_KEPT: usize = 120;
+
+// Tells apart dumps from the REPL, the thumbnail task and one-shot commands running at once
+static DUMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Grabs the guest's screen as a PNG. QEMU writes the dump itself, so it lands next to the
+/// disk image first, at dump_prefix plus a name unique to this capture; PPM is the one format
+/// every QEMU version can produce.
+pub async fn capture_screen_png(qmp_socket: &Path, dump_prefix: &Path, max_width: Option<usize>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+  let mut dump_path = dump_prefix.to_path_buf().into_os_string();
+  dump_path.push(format!("-{}-{}.ppm", std::process::id(), DUMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+  let dump_path = PathBuf::from(dump_path);
+
+  let qmp = qmp_connect_if_running(qmp_socket).await?.ok_or("VM is not running")?;
+  qmp.execute(qmp::screendump { filename: dump_path.to_string_lossy().to_string(), device: None, head: None, format: None }).await?;
+  drop(qmp);
+
+  let ppm = tokio::fs::read(&dump_path).await;
+  if dump_path.exists() {
+    dump_error!( tokio::fs::remove_file(&dump_path).await );
+  }
+  let ppm = ppm?;
+  let png = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
+    let image = parse_ppm(&ppm)?;
+    Ok(match max_width {
+      Some(max_width) => encode_png(&downscale(&image, max_width)),
+      None => encode_png(&image),
+    })
+  }).await??;
+  Ok(png)
+}
+
+/// screenshot [FILE]: saves the guest's screen, by default as ./<vm name>-<unix time>.png
+pub async fn screenshot_command(vm: &VMBlock, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+  let file = match args.first() {
+    Some(file) => PathBuf::from(file),
+    None => PathBuf::from(format!("{}-{}.png", vm.name.replace(|c: char| !c.is_ascii_alphanumeric(), "_"), unix_now())),
+  };
+  let png = capture_screen_png(&vm.flag_path(".qmp.sock"), &vm.flag_path(".screendump"), None).await?;
+  tokio::fs::write(&file, png).await?;
+  println!("Saved screenshot to {}", file.display());
+  Ok(())
+}
+
+/// --screenshot-every N: every N seconds adds a small UNIXTIME.png to the thumbnails directory next to
+/// the disk image and points latest.png at it, for dashboards or for looking back at what a headless
+/// guest went through. Only the newest THUMBNAILS_KEPT are kept.
+pub async fn screenshot_task(qmp_socket: PathBuf, dump_prefix: PathBuf, thumbnail_dir: PathBuf, every_secs: u64) {
+  println!("Writing screen thumbnails to {} every {}s", thumbnail_dir.display(), every_secs);
+  dump_error!( tokio::fs::create_dir_all(&thumbnail_dir).await );
+  loop {
+    tokio::time::sleep(tokio::time::Duration::from_secs(every_secs)).await;
+    // Errors are left for the next tick: QMP busy with another command, or QEMU restarting after an install
+    let png = capture_screen_png(&qmp_socket, &dump_prefix, Some(THUMBNAIL_WIDTH)).await.ok();
+    if let Some(png) = png {
+      dump_error!( write_atomically(&thumbnail_dir.join(format!("{}.png", unix_now())), &png).await );
+      dump_error!( write_atomically(&thumbnail_dir.join("latest.png"), &png).await );
+      dump_error!( prune_thumbnails(&thumbnail_dir).await );
+    }
+  }
+}
+
+async fn prune_thumbnails(thumbnail_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+  let mut thumbnails = vec![];
+  let mut entries = tokio::fs::read_dir(thumbnail_dir).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let name = entry.file_name().to_string_lossy().to_string();
+    if let Some(Ok(taken_unix)) = name.strip_suffix(".png").map(|stem| stem.parse::<u64>()) {
+      thumbnails.push((taken_unix, entry.path()));
+    }
+  }
+  thumbnails.sort();
+  let excess = thumbnails.len().saturating_sub(THUMBNAILS_KEPT);
+  for (_, old_thumbnail) in thumbnails.into_iter().take(excess) {
+    tokio::fs::remove_file(old_thumbnail).await?;
+  }
+  Ok(())
+}
+
+// Readers polling the thumbnail never see half a file
+async fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+  let mut partial = path.to_path_buf().into_os_string();
+  partial.push(".partial");
+  tokio::fs::write(&partial, data).await?;
+  tokio::fs::rename(&partial, path).await?;
+  Ok(())
+}
@@ -0,0 +1,75 @@
+/// Resolves a secret from the config. Explicit sources:
+///   pass:VALUE                 the value itself
+///   env:NAME                   environment variable NAME
+///   file:/path                 contents of a file
+///   cmd:COMMAND                stdout of `sh -c COMMAND`, eg cmd:pass show vms/tiny11
+///   secret-service:ATTR=VAL,.. `secret-tool lookup ATTR VAL ...` (GNOME Keyring, KWallet)
+///   keyring:NAME               `keyctl pipe %user:NAME` from the kernel keyring
+/// Values without a prefix keep the old guessing: a readable file, then an env var, then the value as-is.
+pub async fn read_secret(secret_config_value: &str) -> Result<String, Box<dyn std::error::Error>> {
+  if let Some(value) = secret_config_value.strip_prefix("pass:") {
+    return Ok(value.to_string());
+  }
+  if let Some(env_var) = secret_config_value.strip_prefix("env:") {
+    return Ok(std::env::var(env_var).map_err(|_| format!("Secret env var {} is not set", env_var))?.trim().to_string());
+  }
+  if let Some(path) = secret_config_value.strip_prefix("file:") {
+    let file_val = tokio::fs::read(path).await.map_err(|e| format!("Cannot read secret file {}: {}", path, e))?;
+    return Ok(String::from_utf8_lossy(&file_val).trim().to_string());
+  }
+  if let Some(sh_cmd) = secret_config_value.strip_prefix("cmd:") {
+    return secret_from_command("sh", &["-c", sh_cmd]).await;
+  }
+  if let Some(attributes) = secret_config_value.strip_prefix("secret-service:") {
+    let mut lookup_args = vec!["lookup"];
+    for attribute in attributes.split(',') {
+      let (attr, val) = attribute.split_once('=').ok_or(format!("Bad secret-service attribute {:?}, expected ATTR=VALUE", attribute))?;
+      lookup_args.extend([attr, val]);
+    }
+    return secret_from_command("secret-tool", &lookup_args).await;
+  }
+  if let Some(key_name) = secret_config_value.strip_prefix("keyring:") {
+    return secret_from_command("keyctl", &["pipe", &format!("%user:{}", key_name)]).await;
+  }
+
+  // Is it stored in a file?
+  match tokio::fs::read(secret_config_value).await {
+    Ok(file_val) => Ok(String::from_utf8_lossy(&file_val).as_ref().trim().to_string()),
+    Err(_e) => {
+      // Is it an environment variable?
+      match std::env::var(secret_config_value) {
+        Ok(env_var_val) => Ok(env_var_val.trim().to_string()),
+        Err(_e2) => {
+          // Use value as-is
+          Ok(secret_config_value.to_string())
+        }
+      }
+    }
+  }
+}
+
+async fn secret_from_command(program: &str, args: &[&str]) -> Result<String, Box<dyn std::error::Error>> {
+  let output = tokio::process::Command::new(program)
+    .args(args)
+    .stderr(std::process::Stdio::inherit())
+    .output()
+    .await
+    .map_err(|e| format!("Cannot run {} to read a secret: {}", program, e))?;
+  if !output.status.success() {
+    return Err(format!("{} exited with {} while reading a secret", program, output.status).into());
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Writes a file that embeds a secret so that it is 0600 before any of the secret lands in it,
+/// also when a previous run left a more permissive file behind
+pub async fn write_private_file(path: &std::path::Path, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+  use std::os::unix::fs::PermissionsExt;
+  use tokio::io::AsyncWriteExt;
+  let mut file = tokio::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path).await?;
+  // mode() only applies to newly created files
+  file.set_permissions(std::fs::Permissions::from_mode(0o600)).await?;
+  file.write_all(contents).await?;
+  file.flush().await?;
+  Ok(())
+}
@@ -0,0 +1,193 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::qmp::*;
+use crate::guest_ready::*;
+
+// A cold Windows boot can take a while before RDP answers
+const GUEST_READY_TIMEOUT_SECS: u64 = 300;
+
+struct ServeState {
+  config_path: String,
+  vm_name: String,
+  qmp_socket: PathBuf,
+  qga_socket: PathBuf,
+  serve_pid_file: PathBuf,
+  // `azure-vm CONFIG --serve-backend` child, if we started the VM ourselves
+  backend: tokio::sync::Mutex<Option<tokio::process::Child>>,
+}
+
+/// serve CONFIG: listen on the VM's RDP/SSH host ports, boot (or resume) the VM on the first
+/// connection, then splice every client through to the guest.
+pub async fn serve_command(vm_config: &VMConfig, config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+  vm_config.vm.check_serve_ports()?;
+  let state = Arc::new(ServeState {
+    config_path: config_path.to_string(),
+    vm_name: vm_config.vm.name.clone(),
+    qmp_socket: vm_config.vm.flag_path(".qmp.sock"),
+    qga_socket: vm_config.vm.flag_path(".qga.sock"),
+    serve_pid_file: serve_pid_file(&vm_config.vm),
+    backend: tokio::sync::Mutex::new(None),
+  });
+
+  // Each forward's usual host port is ours, QEMU gets the same port + SERVE_BACKEND_PORT_OFFSET
+  for (port, guest_port) in vm_config.vm.tcp_forwards() {
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await
+      .map_err(|e| format!("Cannot listen on 127.0.0.1:{} (guest port {}), is {} already running outside of serve? {}", port, guest_port, vm_config.vm.name, e))?;
+    println!("Serving guest port {} on 127.0.0.1:{}", guest_port, port);
+    tokio::spawn(accept_loop(listener, port, guest_port, state.clone()));
+  }
+  // Tells one-shot commands that this VM's ports belong to us, see ensure_vm_running()
+  tokio::fs::write(&state.serve_pid_file, std::process::id().to_string()).await?;
+
+  let mut term_stream = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+  tokio::select! {
+    _ = tokio::signal::ctrl_c() => { }
+    _ = term_stream.recv() => { }
+  };
+
+  // Let the backend shut QEMU down cleanly instead of dying with us
+  if let Some(mut backend) = state.backend.lock().await.take() {
+    if let Some(backend_pid) = backend.id() {
+      println!("Stopping {}...", vm_config.vm.name);
+      dump_error!( nix::sys::signal::kill(nix::unistd::Pid::from_raw(backend_pid as i32), nix::sys::signal::Signal::SIGTERM) );
+      dump_error!( backend.wait().await );
+    }
+  }
+  dump_error!( tokio::fs::remove_file(&state.serve_pid_file).await );
+  Ok(())
+}
+
+fn serve_pid_file(vm: &VMBlock) -> PathBuf {
+  vm.flag_path(".serve.pid")
+}
+
+// A pid file left behind by a crashed serve does not count
+async fn serve_is_running(vm: &VMBlock) -> bool {
+  let pid = match tokio::fs::read_to_string(serve_pid_file(vm)).await.ok().and_then(|pid| pid.trim().parse::<i32>().ok()) {
+    Some(pid) => pid,
+    None => return false,
+  };
+  nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None).is_ok()
+}
+
+async fn accept_loop(listener: tokio::net::TcpListener, port: u16, guest_port: u16, state: Arc<ServeState>) {
+  loop {
+    let (client, client_addr) = match listener.accept().await {
+      Ok(accepted) => accepted,
+      Err(e) => {
+        eprintln!("ERROR accepting on port {}: {:?}", port, e);
+        continue;
+      }
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+      println!("{} connected to port {}", client_addr, port);
+      if let Err(e) = proxy_client(client, port, guest_port, &state).await {
+        eprintln!("Dropping {} on port {}: {}", client_addr, port, e);
+      }
+    });
+  }
+}
+
+async fn proxy_client(mut client: tokio::net::TcpStream, port: u16, guest_port: u16, state: &ServeState) -> Result<(), String> {
+  let backend_port = serve_backend_port(port).ok_or_else(|| format!("port {} has no backend port", port))?;
+  ensure_vm_up(state).await?;
+  wait_for_guest_port(backend_port, guest_port, state).await?;
+  let mut upstream = tokio::net::TcpStream::connect(("127.0.0.1", backend_port)).await.map_err(|e| e.to_string())?;
+  tokio::io::copy_bidirectional(&mut client, &mut upstream).await.map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Resumes a paused VM, or starts the backend if QEMU is not running at all
+async fn ensure_vm_up(state: &ServeState) -> Result<(), String> {
+  // Held across the whole check so simultaneous clients start the VM only once
+  let mut backend = state.backend.lock().await;
+
+  let qmp = match qmp_connect_if_running(&state.qmp_socket).await {
+    Ok(qmp) => qmp,
+    Err(_) => return Ok(()), // QEMU is up but its QMP socket is busy with someone else
+  };
+  if let Some(qmp) = qmp {
+    return resume_if_paused(&qmp, &state.vm_name).await;
+  }
+
+  let still_starting = match backend.as_mut() {
+    Some(child) => matches!(child.try_wait(), Ok(None)),
+    None => false,
+  };
+  if still_starting {
+    return Ok(());
+  }
+
+  println!("Starting {} for an incoming connection", state.vm_name);
+  *backend = Some(spawn_background_vm(&state.config_path, &["--serve-backend"], None).map_err(|e| e.to_string())?);
+  Ok(())
+}
+
+/// Makes sure the VM is up for a one-shot command like `rdp`, booting it in the background if needed
+pub async fn ensure_vm_running(vm_config: &VMConfig, config_path: &str) -> Result<(), String> {
+  let qmp = match qmp_connect_if_running(&vm_config.vm.flag_path(".qmp.sock")).await {
+    Ok(qmp) => qmp,
+    Err(_) => return Ok(()),
+  };
+  if let Some(qmp) = qmp {
+    return resume_if_paused(&qmp, &vm_config.vm.name).await;
+  }
+  // Under `serve` the proxy owns this VM's ports and boots it itself once we connect
+  if serve_is_running(&vm_config.vm).await {
+    return Ok(());
+  }
+
+  let log_file = vm_config.vm.flag_path(".log");
+  println!("Starting {} in the background, its output goes to {}", vm_config.vm.name, log_file.display());
+  spawn_background_vm(config_path, &[], Some(&log_file)).map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+async fn resume_if_paused(qmp: &QmpHandle, vm_name: &str) -> Result<(), String> {
+  let status = qmp.execute(QueryStatus { }).await.map_err(|e| e.to_string())?;
+  if !status.running && status.status != "inmigrate" {
+    println!("Resuming {} (was {})", vm_name, status.status);
+    qmp.execute(qmp::cont { }).await.map_err(|e| e.to_string())?;
+  }
+  Ok(())
+}
+
+/// Runs `azure-vm CONFIG FLAGS...` without a REPL. With a log_file the VM is detached into its own
+/// process group so it outlives us; without one it shares our output and dies with us.
+fn spawn_background_vm(config_path: &str, flags: &[&str], log_file: Option<&Path>) -> std::io::Result<tokio::process::Child> {
+  use std::os::unix::process::CommandExt;
+  let mut cmd = std::process::Command::new(std::env::current_exe()?);
+  cmd.arg(config_path)
+    .args(flags)
+    .env("AZURE_VM_HOLD_WHEN_STDIN_STOPS", "t") // Nobody types into the background VM's REPL
+    .stdin(std::process::Stdio::null());
+  if let Some(log_file) = log_file {
+    let log = std::fs::OpenOptions::new().create(true).append(true).open(log_file)?;
+    cmd.stdout(log.try_clone()?).stderr(log).process_group(0);
+  }
+  tokio::process::Command::from(cmd).spawn()
+}
+
+/// slirp accepts host-side connections before the guest does and keeps retrying the SYN while it
+/// boots, so an open socket proves nothing. RDP and SSH are probed for a real protocol answer; other
+/// forwards wait until the guest is up by some measure (RDP, SSH or the guest agent answering).
+async fn wait_for_guest_port(backend_port: u16, guest_port: u16, state: &ServeState) -> Result<(), String> {
+  let started = std::time::Instant::now();
+  while started.elapsed().as_secs() < GUEST_READY_TIMEOUT_SECS {
+    let ready = match guest_port {
+      3389 => rdp_probe(backend_port).await,
+      22 => ssh_probe(backend_port).await,
+      _ => rdp_probe(3389 + SERVE_BACKEND_PORT_OFFSET).await || ssh_probe(2222 + SERVE_BACKEND_PORT_OFFSET).await || qga_ping(&state.qga_socket).await,
+    };
+    if ready {
+      return Ok(());
+    }
+    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+  }
+  Err(format!("guest did not answer on backend port {} within {}s", backend_port, GUEST_READY_TIMEOUT_SECS))
+}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use qapi::qmp;
+
+use crate::structs::*;
+use crate::disks::*;
+use crate::qmp::*;
+use crate::hibernate::*;
+
+/// snapshot create|list|revert|delete [NAME]
+/// Uses QMP snapshot jobs while the VM is running and qemu-img while it is stopped.
+pub async fn snapshot_command(vm_config: &VMConfig, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+  if vm_config.vm.disk_partuuid.len() > 1 {
+    return Err(format!("Refusing to snapshot: root disk {} is a physical disk, snapshots need a qcow2 disk_image", vm_config.vm.disk_partuuid).into());
+  }
+
+  let action = args.first().copied().unwrap_or("list");
+  let tag = args.get(1).copied().unwrap_or_default();
+  if !["create", "list", "revert", "delete"].contains(&action) {
+    return Err(format!("Unknown snapshot action {:?}, expected create, list, revert or delete", action).into());
+  }
+  if action != "list" && tag.is_empty() {
+    return Err(format!("Usage: snapshot {} NAME", action).into());
+  }
+
+  let qmp_socket = vm_config.vm.flag_path(".qmp.sock");
+  match qmp_connect_if_running(&qmp_socket).await? {
+    Some(qmp) => snapshot_running(vm_config, &qmp, action, tag).await,
+    None => snapshot_stopped(vm_config, action, tag).await,
+  }
+}
+
+async fn snapshot_running(vm_config: &VMConfig, qmp: &QmpHandle, action: &str, tag: &str) -> Result<(), Box<dyn std::error::Error>> {
+  if action == "list" {
+    for block_dev in qmp.execute(qmp::query_block { }).await?.iter() {
+      if let Some(inserted) = &block_dev.inserted {
+        if let Some(snapshots) = &inserted.image.base.snapshots {
+          println!("{} ({}):", block_dev.device, inserted.file);
+          print_snapshots(snapshots);
+        }
+      }
+    }
+    return Ok(());
+  }
+
+  // The VM state goes into the root disk, every writable qcow2 disk gets its blocks snapshotted
+  let root_node = qmp_node_name_of_device(qmp, "root_disk").await?;
+  let mut devices = vec![root_node.clone()];
+  for (i, disk) in vm_config.disks.iter().enumerate() {
+    if disk.kind == DiskKind::Qcow2 && !disk.readonly {
+      devices.push(disk_node_name(i, disk));
+    }
+  }
+
+  let job_id = format!("snapshot-{}-{}", action, tag);
+  match action {
+    "create" => {
+      println!("Saving snapshot {} of {:?}...", tag, devices);
+      qmp.execute(qmp::snapshot_save { job_id: job_id.clone(), tag: tag.to_string(), vmstate: root_node, devices }).await?;
+    }
+    "revert" => {
+      println!("Reverting {:?} to snapshot {}...", devices, tag);
+      qmp.execute(qmp::snapshot_load { job_id: job_id.clone(), tag: tag.to_string(), vmstate: root_node, devices }).await?;
+    }
+    _ => {
+      println!("Deleting snapshot {} from {:?}...", tag, devices);
+      qmp.execute(qmp::snapshot_delete { job_id: job_id.clone(), tag: tag.to_string(), devices }).await?;
+    }
+  }
+  qmp_wait_for_job(qmp, &job_id).await?;
+  println!("Done!");
+  Ok(())
+}
+
+async fn snapshot_stopped(vm_config: &VMConfig, action: &str, tag: &str) -> Result<(), Box<dyn std::error::Error>> {
+  let mut images: Vec<PathBuf> = vec![vm_config.vm.disk_image.clone()];
+  for disk in vm_config.disks.iter() {
+    if disk.kind == DiskKind::Qcow2 && !disk.readonly {
+      images.push(disk.path.clone());
+    }
+  }
+
+  let flag = match action {
+    "create" => "-c",
+    "revert" => "-a",
+    "delete" => "-d",
+    _ => "-l",
+  };
+  if action == "revert" {
+    invalidate_hibernated_state(&vm_config.vm).await;
+  }
+  for image in images.iter() {
+    let image = image.to_string_lossy();
+    if action == "list" {
+      run_qemu_img(&["snapshot", flag, &image]).await?;
+    }
+    else {
+      run_qemu_img(&["snapshot", flag, tag, &image]).await?;
+    }
+  }
+  Ok(())
+}
+
+fn print_snapshots(snapshots: &[qmp::SnapshotInfo]) {
+  if snapshots.is_empty() {
+    println!("  (no snapshots)");
+  }
+  for snapshot in snapshots.iter() {
+    println!("  {:<24} vm-state={}MB vm-clock={}s", snapshot.name, snapshot.vm_state_size / (1024 * 1024), snapshot.vm_clock_sec);
+  }
+}
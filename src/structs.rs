@@ -3,10 +3,33 @@ use std::path::PathBuf;
 
 use serde::{Serialize, Deserialize};
 
+// QEMU's hostfwd ports move up by this much when `serve` proxies the usual ones
+pub const SERVE_BACKEND_PORT_OFFSET: u16 = 10000;
+
+/// Where QEMU listens under `serve` for a forward's usual host port; None once that passes 65535
+pub fn serve_backend_port(port: u16) -> Option<u16> {
+  port.checked_add(SERVE_BACKEND_PORT_OFFSET)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VMConfig {
   pub install: VMInstallBlock,
   pub vm: VMBlock,
+
+  #[serde(default = "empty_vec_disks")]
+  pub disks: Vec<VMDisk>,
+
+  #[serde(default = "empty_vec_apps")]
+  pub apps: Vec<VMApp>,
+
+  #[serde(default = "default_rdp_block")]
+  pub rdp: RdpBlock,
+
+  #[serde(default)]
+  pub unattend: Option<UnattendBlock>, // Present = render autounattend.xml media for the install phase
+
+  #[serde(default)]
+  pub cloud_init: Option<CloudInitBlock>, // Present = attach a NoCloud cidata seed, for stock Linux cloud images
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -14,6 +37,23 @@ pub struct VMInstallBlock {
   #[serde(default = "empty_string")]
   pub boot_iso_url: String,
   pub boot_iso: PathBuf,
+
+  #[serde(default = "empty_string")]
+  pub boot_iso_sha256: String, // Checked once after download (and once for an existing boot_iso)
+
+  #[serde(default = "default_install_done_when")]
+  pub done_when: InstallDoneSignal, // What marks the install as finished, see install_detect.rs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallDoneSignal {
+  Auto, // qga or serial, which only the installed system produces
+  Qga, // The QEMU guest agent answers guest-ping
+  Serial, // The guest writes INSTALL_DONE_SERIAL_MARKER to its first serial port
+  Rdp, // The guest answers RDP on the forwarded port; opt-in, installers may run it too
+  Ssh, // The guest sends an SSH banner on the forwarded port; opt-in, many Linux installers run sshd
+  Manual, // Only `touch` on the .installed flag
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,11 +69,51 @@ pub struct VMBlock {
   pub disk_partuuid: String,
 
   #[serde(default = "false_bool")]
-  pub mount_windows_virtio_iso: bool,
+  pub mount_windows_virtio_iso: bool, // Attach the virtio-win driver ISO as a second CD-ROM during install
+
+  #[serde(default = "false_bool")]
+  pub mount_windows_virtio_iso_at_runtime: bool, // ...and after install too, eg to update drivers
+
+  #[serde(default = "default_virtio_win_iso")]
+  pub virtio_win_iso: PathBuf, // Downloaded from virtio_win_iso_url if missing, shared by every VM using the same path
+
+  #[serde(default = "default_virtio_win_iso_url")]
+  pub virtio_win_iso_url: String,
 
   #[serde(default = "false_bool")]
   pub drop_to_serial: bool,
 
+  #[serde(default = "false_bool")]
+  pub ephemeral: bool, // Boot from a throwaway overlay on top of disk_image, see --ephemeral
+
+  #[serde(default = "empty_pathbuf")]
+  pub backup_dir: PathBuf, // Defaults to a .backups directory next to disk_image
+
+  #[serde(default = "zero_usize")]
+  pub idle_suspend_minutes: usize, // Pause the VM after this long without RDP/SSH/SPICE clients, 0 = never
+
+  #[serde(skip)]
+  pub serve_backend: bool, // Started by `serve`, which owns the usual host ports; see host_port()
+
+  #[serde(default = "zero_u64")]
+  pub screenshot_every_secs: u64, // Add a screen thumbnail to .thumbnails/ this often, 0 = never; see --screenshot-every
+
+
+  #[serde(default = "default_display_mode")]
+  pub display: DisplayMode,
+
+  #[serde(default = "zero_u16")]
+  pub display_port: u16, // spice-tcp / vnc listen port on 127.0.0.1, 0 = 5930 for spice-tcp, 5900 for vnc
+
+  #[serde(default = "empty_string")]
+  pub vnc_password: String, // Secret, same syntax as rdp_pass; empty = no password
+
+  #[serde(default = "zero_u16")]
+  pub vnc_websocket_port: u16, // Also serve VNC over websockets (eg for noVNC), 0 = off
+
+  #[serde(default = "empty_string")]
+  pub vga_override: String, // -vga model, defaults to qxl for spice and std otherwise
+
 
   #[serde(default = "default_bios_override_val")]
   pub bios_override: String,
@@ -54,6 +134,9 @@ pub struct VMBlock {
   #[serde(default = "default_root_disk_if_override")]
   pub root_disk_if_override: String, // Typically "none" or "virtio"
 
+  #[serde(default = "default_disk_cache")]
+  pub disk_cache: DiskCache, // cache= mode for the disk_image / disk_partuuid root disk
+
   pub ram_mb: usize,
 
   #[serde(default = "empty_vec_string")]
@@ -67,6 +150,9 @@ pub struct VMBlock {
   #[serde(default = "empty_vec_string")]
   pub addtl_rdp_args: Vec<String>,
 
+  #[serde(default = "default_rdp_wait_timeout_secs")]
+  pub rdp_wait_timeout_secs: u64, // How long `rdp` waits for the guest to accept RDP, 0 = don't wait
+
   #[serde(default = "empty_vec_string")]
   pub preboot_cmds: Vec<String>,
 
@@ -75,7 +161,301 @@ pub struct VMBlock {
 
 }
 
+// One entry of the [[disks]] list; disk_image / disk_partuuid remain shorthand for the root disk.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VMDisk {
+  pub kind: DiskKind,
+  pub path: PathBuf,
+
+  #[serde(default = "empty_string")]
+  pub id: String, // Defaults to "disk{N}" by position in the list
+
+  #[serde(default = "default_disk_bus")]
+  pub bus: DiskBus,
+
+  #[serde(default = "default_disk_cache")]
+  pub cache: DiskCache,
+
+  #[serde(default = "false_bool")]
+  pub discard: bool,
+
+  #[serde(default = "false_bool")]
+  pub readonly: bool,
+
+  #[serde(default = "empty_string")]
+  pub serial: String,
+
+  #[serde(default)]
+  pub boot_index: Option<usize>,
+
+  #[serde(default = "zero_usize")]
+  pub size_gb: usize, // qcow2 images are created at this size if they do not exist yet
+}
+
+// One entry of the [[apps]] RemoteApp catalog, launched with `rdp NAME [ARGS...]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VMApp {
+  pub name: String,
+  pub program: String, // Path of the executable inside the guest, eg C:\Program Files\Microsoft Office\root\Office16\EXCEL.EXE
+
+  #[serde(default = "empty_string")]
+  pub working_dir: String,
+
+  #[serde(default = "empty_vec_string")]
+  pub args: Vec<String>, // Passed before any args given on the rdp command line
+
+  #[serde(default = "empty_vec_string")]
+  pub rdp_args: Vec<String>, // Extra FreeRDP args for just this app
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisplayMode {
+  Gtk, // Local window
+  Sdl, // Local window
+  SpiceUnix, // Spice on the .spice.sock unix socket, opened with `gui`
+  SpiceTcp, // Spice on 127.0.0.1:display_port, for ssh -L or a local viewer
+  Vnc, // VNC on 127.0.0.1:display_port, optionally with a password and websockets
+  None, // Headless; rdp, ssh and the serial console still work
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskKind {
+  Qcow2,
+  Raw,
+  Physical,
+  Cdrom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskCache {
+  None,
+  Writeback,
+  Writethrough,
+  Directsync,
+  Unsafe, // Host crash == guest filesystem corruption, only for throwaway images
+}
+
+impl DiskCache {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DiskCache::None => "none",
+      DiskCache::Writeback => "writeback",
+      DiskCache::Writethrough => "writethrough",
+      DiskCache::Directsync => "directsync",
+      DiskCache::Unsafe => "unsafe",
+    }
+  }
+
+  // O_DIRECT modes, which are the only ones aio=native accepts
+  pub fn is_direct(&self) -> bool {
+    matches!(self, DiskCache::None | DiskCache::Directsync)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskBus {
+  VirtioBlk,
+  VirtioScsi,
+  Nvme,
+  Ahci,
+  Ide,
+}
+
+// [rdp] session settings; rdp_uname, rdp_pass and addtl_rdp_args stay in [vm]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RdpBlock {
+  #[serde(default = "default_rdp_resolution")]
+  pub resolution: String, // WIDTHxHEIGHT or "fullscreen"
+
+  #[serde(default = "false_bool")]
+  pub multimon: bool, // Span all monitors, overrides resolution
+
+  #[serde(default = "true_bool")]
+  pub dynamic_resolution: bool,
+
+  #[serde(default = "zero_usize")]
+  pub scale_percent: usize, // Guest desktop scale factor, 0 = leave it to Windows
+
+  #[serde(default = "default_rdp_shared_drives")]
+  pub shared_drives: Vec<String>, // FreeRDP /drive: syntax, NAME,/host/path
+
+  #[serde(default = "true_bool")]
+  pub clipboard: bool,
+
+  #[serde(default = "true_bool")]
+  pub audio: bool, // Play guest audio on the host
+
+  #[serde(default = "default_rdp_microphone")]
+  pub microphone: String, // FreeRDP /microphone: value, empty to disable
+
+  #[serde(default = "empty_string")]
+  pub gateway: String, // FreeRDP /gateway: value, eg g:gw.example.com,u:me
+
+  #[serde(default = "default_rdp_cert")]
+  pub cert: RdpCertPolicy,
+
+  #[serde(default = "default_rdp_client")]
+  pub client: String, // "auto" (wlfreerdp on Wayland, else xfreerdp) or a binary like sdl-freerdp / xfreerdp3
+}
+
+// [unattend] settings for a hands-off Windows install, see unattend.rs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnattendBlock {
+  #[serde(default = "default_unattend_locale")]
+  pub locale: String, // UI, system and user locale, eg en-US or de-DE
+
+  #[serde(default = "empty_string")]
+  pub input_locale: String, // Keyboard layout, eg de-DE or 0407:00000407; defaults to locale
+
+  #[serde(default = "default_unattend_timezone")]
+  pub timezone: String, // Windows time zone name, eg "W. Europe Standard Time"
+
+  #[serde(default = "empty_string")]
+  pub computer_name: String, // Defaults to the VM name, cut down to what NetBIOS accepts
+
+  #[serde(default = "empty_string")]
+  pub user: String, // Local administrator account, defaults to rdp_uname (or "user")
+
+  #[serde(default = "empty_string")]
+  pub password: String, // Secret, same syntax as rdp_pass which it defaults to
+
+  #[serde(default = "empty_string")]
+  pub product_key: String, // Empty = none, fine for evaluation media and most edition-less ISOs
+
+  #[serde(default = "empty_string")]
+  pub image_name: String, // Edition to install from a multi-edition ISO, eg "Windows 11 Pro"
+
+  #[serde(default = "true_bool")]
+  pub partition_disk: bool, // Wipe and partition the root disk; never done for disk_partuuid VMs
+
+  #[serde(default = "true_bool")]
+  pub enable_rdp: bool, // Allow RDP (and unlisted RemoteApps) and open the firewall for it
+
+  #[serde(default = "true_bool")]
+  pub install_virtio_drivers: bool, // From the virtio-win ISO, which gets attached for this
+
+  #[serde(default = "true_bool")]
+  pub install_guest_agent: bool, // QEMU guest agent, also from the virtio-win ISO
+
+  #[serde(default = "default_unattend_virtio_driver_os")]
+  pub virtio_driver_os: String, // Driver directory on the virtio-win ISO, eg w10 or w11
+
+  #[serde(default = "true_bool")]
+  pub bypass_tpm_checks: bool, // Windows 11 TPM, Secure Boot, RAM and CPU checks
+
+  #[serde(default = "empty_vec_string")]
+  pub first_logon_cmds: Vec<String>, // Extra cmd.exe lines run once at the first logon
+}
+
+// [cloud_init] settings for Linux cloud images, see cloud_init.rs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CloudInitBlock {
+  #[serde(default = "empty_string")]
+  pub user: String, // Defaults to "user"
+
+  #[serde(default = "empty_string")]
+  pub password: String, // Secret, same syntax as rdp_pass; empty = SSH keys only
+
+  #[serde(default = "empty_vec_string")]
+  pub ssh_authorized_keys: Vec<String>, // Key lines or paths to .pub files; defaults to ~/.ssh/id_*.pub
+
+  #[serde(default = "true_bool")]
+  pub sudo: bool, // Passwordless sudo for user
+
+  #[serde(default = "empty_string")]
+  pub hostname: String, // Defaults to the VM name
+
+  #[serde(default = "empty_vec_string")]
+  pub packages: Vec<String>,
+
+  #[serde(default = "empty_vec_string")]
+  pub runcmd: Vec<String>, // Shell lines run once as root at the end of the first boot
+
+  #[serde(default = "empty_string")]
+  pub instance_id: String, // Defaults to one derived from disk_image; change it to re-run the first-boot setup
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RdpCertPolicy {
+  Ignore, // Accept any certificate
+  Tofu, // Pin the first certificate seen in FreeRDP's known_hosts, refuse if it changes
+}
+
 impl VMBlock {
+  /// Host port QEMU forwards to the guest for the usual host port; under `serve` the usual
+  /// ports belong to the proxy, so QEMU gets them shifted by SERVE_BACKEND_PORT_OFFSET.
+  /// check_serve_ports() has rejected configs whose ports cannot be shifted.
+  pub fn host_port(&self, port: u16) -> u16 {
+    if self.serve_backend { serve_backend_port(port).unwrap_or(port) } else { port }
+  }
+
+  /// Every forward `serve` proxies must still be a valid port once shifted for QEMU
+  pub fn check_serve_ports(&self) -> Result<(), String> {
+    for (port, guest_port) in self.tcp_forwards() {
+      if serve_backend_port(port).is_none() {
+        return Err(format!("Host port {} (guest port {}) cannot be served, serve moves QEMU's forwards up by {} and only ports up to {} fit",
+          port, guest_port, SERVE_BACKEND_PORT_OFFSET, u16::MAX - SERVE_BACKEND_PORT_OFFSET));
+      }
+    }
+    Ok(())
+  }
+
+  /// TCP forwards as (usual host port, guest port): RDP and SSH, plus any hostfwd=tcp: in addtl_args
+  pub fn tcp_forwards(&self) -> Vec<(u16, u16)> {
+    let mut forwards = vec![(3389, 3389), (2222, 22)];
+    for arg in self.addtl_args.iter() {
+      for opt in arg.split(',') {
+        // ADDR:PORT-GUESTADDR:GUESTPORT, either ADDR may be empty
+        let fwd = match opt.strip_prefix("hostfwd=tcp:") {
+          Some(fwd) => fwd,
+          None => continue,
+        };
+        let (host_side, guest_side) = fwd.split_once('-').unwrap_or((fwd, ""));
+        let host_port = host_side.rsplit(':').next().and_then(|p| p.parse().ok());
+        let guest_port = guest_side.rsplit(':').next().and_then(|p| p.parse().ok());
+        if let (Some(host_port), Some(guest_port)) = (host_port, guest_port) {
+          forwards.push((host_port, guest_port));
+        }
+      }
+    }
+    forwards
+  }
+
+  /// addtl_args with their hostfwd host ports moved through host_port(), like the built-in forwards
+  pub fn addtl_qemu_args(&self) -> Vec<String> {
+    if !self.serve_backend {
+      return self.addtl_args.clone();
+    }
+    self.addtl_args.iter().map(|arg| {
+      arg.split(',').map(|opt| {
+        let fwd = match opt.strip_prefix("hostfwd=tcp:") {
+          Some(fwd) => fwd,
+          None => return opt.to_string(),
+        };
+        let (host_side, guest_side) = fwd.split_once('-').unwrap_or((fwd, ""));
+        let (host_addr, host_port) = host_side.rsplit_once(':').unwrap_or(("", host_side));
+        match host_port.parse::<u16>() {
+          Ok(port) => format!("hostfwd=tcp:{}:{}-{}", host_addr, self.host_port(port), guest_side),
+          Err(_) => opt.to_string(),
+        }
+      }).collect::<Vec<_>>().join(",")
+    }).collect()
+  }
+
+  pub fn backup_dir_path(&self) -> PathBuf {
+    if self.backup_dir.as_os_str().is_empty() {
+      self.flag_path(".backups")
+    }
+    else {
+      self.backup_dir.clone()
+    }
+  }
+
   pub fn flag_path(&self, flag: &str) -> PathBuf {
     if self.disk_partuuid.len() > 1 {
       if PathBuf::from("/mnt/scratch/vms").exists() {
@@ -114,6 +494,10 @@ fn empty_string() -> String {
   String::new()
 }
 
+fn empty_pathbuf() -> PathBuf {
+  PathBuf::new()
+}
+
 fn dev_null_pathbuf() -> PathBuf {
   PathBuf::from("/dev/null")
 }
@@ -122,14 +506,98 @@ fn zero_usize() -> usize {
   0
 }
 
+fn zero_u16() -> u16 {
+  0
+}
+
+fn zero_u64() -> u64 {
+  0
+}
+
 fn false_bool() -> bool {
   false
 }
 
+fn true_bool() -> bool {
+  true
+}
+
 fn empty_vec_string() -> Vec<String> {
   vec![]
 }
 
+fn empty_vec_disks() -> Vec<VMDisk> {
+  vec![]
+}
+
+fn empty_vec_apps() -> Vec<VMApp> {
+  vec![]
+}
+
+fn default_display_mode() -> DisplayMode {
+  DisplayMode::SpiceUnix
+}
+
+fn default_disk_bus() -> DiskBus {
+  DiskBus::Ahci // Every Windows install media ships AHCI drivers
+}
+
+fn default_rdp_block() -> RdpBlock {
+  RdpBlock {
+    resolution: default_rdp_resolution(),
+    multimon: false_bool(),
+    dynamic_resolution: true_bool(),
+    scale_percent: zero_usize(),
+    shared_drives: default_rdp_shared_drives(),
+    clipboard: true_bool(),
+    audio: true_bool(),
+    microphone: default_rdp_microphone(),
+    gateway: empty_string(),
+    cert: default_rdp_cert(),
+    client: default_rdp_client(),
+  }
+}
+
+fn default_rdp_resolution() -> String {
+  "1920x1080".into()
+}
+
+fn default_rdp_shared_drives() -> Vec<String> {
+  vec!["DOWNLOADS,/j/downloads".into()]
+}
+
+fn default_rdp_microphone() -> String {
+  "sys:alsa".into()
+}
+
+fn default_rdp_cert() -> RdpCertPolicy {
+  RdpCertPolicy::Ignore
+}
+
+fn default_rdp_client() -> String {
+  "auto".into()
+}
+
+fn default_install_done_when() -> InstallDoneSignal {
+  InstallDoneSignal::Auto
+}
+
+fn default_unattend_locale() -> String {
+  "en-US".into()
+}
+
+fn default_unattend_timezone() -> String {
+  "UTC".into()
+}
+
+fn default_unattend_virtio_driver_os() -> String {
+  "w11".into()
+}
+
+fn default_disk_cache() -> DiskCache {
+  DiskCache::None
+}
+
 fn default_bios_override_val() -> String {
   "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd".into()
 }
@@ -142,6 +610,18 @@ fn default_spice_rendernode_override() -> String {
   "/dev/dri/by-path/pci-0000:00:02.0-render".into()
 }
 
+fn default_virtio_win_iso() -> PathBuf {
+  PathBuf::from("/mnt/scratch/vms/virtio-win.iso")
+}
+
+fn default_virtio_win_iso_url() -> String {
+  "https://fedorapeople.org/groups/virt/virtio-win/direct-downloads/stable-virtio/virtio-win.iso".into()
+}
+
+fn default_rdp_wait_timeout_secs() -> u64 {
+  180
+}
+
 fn default_smp_override() -> String {
   "4".into()
 }
@@ -158,7 +638,17 @@ fn default_root_disk_if_override() -> String {
   "none".into()
 }
 
+/// Seconds since the epoch, for backup names, screenshot names and idle tracking
+pub fn unix_now() -> u64 {
+  std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 impl VMConfig {
+  // The install phase also needs the virtio-win ISO when [unattend] installs from it
+  pub fn install_needs_virtio_win_iso(&self) -> bool {
+    self.vm.mount_windows_virtio_iso || self.unattend.as_ref().map(|u| u.install_virtio_drivers || u.install_guest_agent).unwrap_or(false)
+  }
+
   pub fn apply_env_overrides(&mut self) {
     if let Ok(var_val) = std::env::var("spice_gl_override") {
       if var_val.len() > 0 {
@@ -175,7 +665,27 @@ impl VMConfig {
         self.vm.drop_to_serial = var_val.contains('t') || var_val.contains('T') || var_val.contains('1');
       }
     }
+    if let Ok(var_val) = std::env::var("ephemeral") {
+      if var_val.len() > 0 {
+        self.vm.ephemeral = var_val.contains('t') || var_val.contains('T') || var_val.contains('1');
+      }
+    }
+
 
+  }
 
+  pub fn apply_cli_flags(&mut self, flags: &[String]) {
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+      match flag.as_str() {
+        "--ephemeral" => self.vm.ephemeral = true,
+        "--serve-backend" => self.vm.serve_backend = true,
+        "--screenshot-every" => match flags.next().map(|secs| secs.parse::<u64>()) {
+          Some(Ok(secs)) => self.vm.screenshot_every_secs = secs,
+          _ => eprintln!("--screenshot-every needs a number of seconds"),
+        },
+        unknown => eprintln!("Ignoring unknown flag {}", unknown),
+      }
+    }
   }
 }
@@ -0,0 +1,198 @@
+use std::path::PathBuf;
+
+use crate::structs::*;
+use crate::secrets::*;
+use crate::iso9660::*;
+use crate::install_detect::*;
+
+// Drive letters the install media, virtio-win ISO and unattend ISO can end up on
+const MEDIA_DRIVE_LETTERS: [char; 5] = ['D', 'E', 'F', 'G', 'H'];
+
+/// Renders [unattend] into autounattend.xml on a small ISO next to the other flag files.
+/// Windows Setup looks for autounattend.xml in the root of every removable drive and CD-ROM.
+pub async fn build_unattend_media(vm_config: &VMConfig, unattend: &UnattendBlock) -> Result<PathBuf, Box<dyn std::error::Error>> {
+  let password_secret = if unattend.password.is_empty() { &vm_config.vm.rdp_pass } else { &unattend.password };
+  let password = if password_secret.is_empty() { String::new() } else { read_secret(password_secret).await? };
+
+  let xml = render_autounattend(vm_config, unattend, &password);
+  let iso = build_iso("UNATTEND", &[("autounattend.xml".to_string(), xml.into_bytes())])?;
+
+  let iso_path = vm_config.vm.flag_path(".unattend.iso");
+  // It holds the password in plain text
+  write_private_file(&iso_path, &iso).await?;
+  Ok(iso_path)
+}
+
+fn render_autounattend(vm_config: &VMConfig, unattend: &UnattendBlock, password: &str) -> String {
+  let user = unattend_user(vm_config, unattend);
+  let input_locale = if unattend.input_locale.is_empty() { &unattend.locale } else { &unattend.input_locale };
+  let locales = format!(
+    "<InputLocale>{input}</InputLocale><SystemLocale>{locale}</SystemLocale><UILanguage>{locale}</UILanguage><UserLocale>{locale}</UserLocale>",
+    input=xml_escape(input_locale), locale=xml_escape(&unattend.locale),
+  );
+
+  // windowsPE: everything Setup would ask before copying files
+  let mut windows_pe = String::new();
+  windows_pe.push_str(&component("Microsoft-Windows-International-Core-WinPE", &format!(
+    "<SetupUILanguage><UILanguage>{}</UILanguage></SetupUILanguage>{}", xml_escape(&unattend.locale), locales)));
+
+  let mut setup = String::new();
+  if unattend.bypass_tpm_checks {
+    setup.push_str("<RunSynchronous>");
+    for (order, check) in ["BypassTPMCheck", "BypassSecureBootCheck", "BypassRAMCheck", "BypassCPUCheck"].iter().enumerate() {
+      setup.push_str(&format!(
+        "<RunSynchronousCommand wcm:action=\"add\"><Order>{}</Order><Path>reg add HKLM\\SYSTEM\\Setup\\LabConfig /v {} /t REG_DWORD /d 1 /f</Path></RunSynchronousCommand>",
+        order + 1, check));
+    }
+    setup.push_str("</RunSynchronous>");
+  }
+  // Never wipe a physical disk on a guess about which one Setup calls disk 0
+  let partition_disk = unattend.partition_disk && vm_config.vm.disk_partuuid.is_empty();
+  if partition_disk {
+    setup.push_str(&disk_configuration(!vm_config.vm.bios_override.is_empty()));
+  }
+  setup.push_str("<ImageInstall><OSImage>");
+  if !unattend.image_name.is_empty() {
+    setup.push_str(&format!("<InstallFrom><MetaData wcm:action=\"add\"><Key>/IMAGE/NAME</Key><Value>{}</Value></MetaData></InstallFrom>", xml_escape(&unattend.image_name)));
+  }
+  if partition_disk {
+    let windows_partition = if vm_config.vm.bios_override.is_empty() { 2 } else { 3 };
+    setup.push_str(&format!("<InstallTo><DiskID>0</DiskID><PartitionID>{}</PartitionID></InstallTo>", windows_partition));
+  }
+  setup.push_str("</OSImage></ImageInstall>");
+  setup.push_str("<UserData><AcceptEula>true</AcceptEula>");
+  if !unattend.product_key.is_empty() {
+    setup.push_str(&format!("<ProductKey><Key>{}</Key><WillShowUI>OnError</WillShowUI></ProductKey>", xml_escape(&unattend.product_key)));
+  }
+  setup.push_str("</UserData>");
+  windows_pe.push_str(&component("Microsoft-Windows-Setup", &setup));
+
+  if unattend.install_virtio_drivers {
+    // Storage and network drivers for setup itself, wherever the virtio-win ISO was mounted
+    let mut driver_paths = String::new();
+    let mut key = 1;
+    for letter in MEDIA_DRIVE_LETTERS.iter() {
+      for driver in ["viostor", "vioscsi", "NetKVM"] {
+        driver_paths.push_str(&format!(
+          "<PathAndCredentials wcm:action=\"add\" wcm:keyValue=\"{}\"><Path>{}:\\{}\\{}\\amd64</Path></PathAndCredentials>",
+          key, letter, driver, xml_escape(&unattend.virtio_driver_os)));
+        key += 1;
+      }
+    }
+    windows_pe.push_str(&component("Microsoft-Windows-PnpCustomizationsWinPE", &format!("<DriverPaths>{}</DriverPaths>", driver_paths)));
+  }
+
+  // specialize: machine identity and services
+  let mut specialize = String::new();
+  specialize.push_str(&component("Microsoft-Windows-Shell-Setup", &format!(
+    "<ComputerName>{}</ComputerName><TimeZone>{}</TimeZone>", xml_escape(&computer_name(vm_config, unattend)), xml_escape(&unattend.timezone))));
+  // Allows a local account in OOBE without a network connection on Windows 11
+  specialize.push_str(&component("Microsoft-Windows-Deployment",
+    "<RunSynchronous><RunSynchronousCommand wcm:action=\"add\"><Order>1</Order><Path>reg add HKLM\\SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\OOBE /v BypassNRO /t REG_DWORD /d 1 /f</Path></RunSynchronousCommand></RunSynchronous>"));
+  if unattend.enable_rdp {
+    specialize.push_str(&component("Microsoft-Windows-TerminalServices-LocalSessionManager", "<fDenyTSConnections>false</fDenyTSConnections>"));
+    specialize.push_str(&component("Networking-MPSSVC-Svc",
+      "<FirewallGroups><FirewallGroup wcm:action=\"add\" wcm:keyValue=\"RemoteDesktop\"><Active>true</Active><Group>Remote Desktop</Group><Profile>all</Profile></FirewallGroup></FirewallGroups>"));
+  }
+
+  // oobeSystem: the account, plus one auto logon to run the first logon commands
+  let install_from_media = |what: &str| format!(
+    "cmd /c for %d in ({}) do @if exist %d:\\{what} msiexec /i %d:\\{what} /qn /norestart",
+    MEDIA_DRIVE_LETTERS.iter().map(|l| l.to_string()).collect::<Vec<_>>().join(" "), what=what);
+  let mut first_logon_cmds: Vec<String> = vec![];
+  if unattend.install_virtio_drivers {
+    first_logon_cmds.push(install_from_media("virtio-win-gt-x64.msi"));
+  }
+  if unattend.enable_rdp {
+    // Lets `rdp APP` start programs that are not registered as RemoteApps
+    first_logon_cmds.push("reg add \"HKLM\\SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\\Terminal Server\\TSAppAllowList\" /v fDisabledAllowList /t REG_DWORD /d 1 /f".to_string());
+  }
+  first_logon_cmds.extend(unattend.first_logon_cmds.iter().map(|cmd| format!("cmd /c {}", cmd)));
+  // Last, so the agent answering tells install detection every command above has run
+  if unattend.install_guest_agent {
+    first_logon_cmds.push(install_from_media("guest-agent\\qemu-ga-x86_64.msi"));
+  }
+  // For done_when = "serial", and "auto" without the guest agent
+  first_logon_cmds.push(format!("cmd /c echo {}> COM1", INSTALL_DONE_SERIAL_MARKER));
+
+  let password_xml = format!("<Password><Value>{}</Value><PlainText>true</PlainText></Password>", xml_escape(password));
+  let mut shell_setup = format!(
+    "<OOBE><HideEULAPage>true</HideEULAPage><HideOEMRegistrationScreen>true</HideOEMRegistrationScreen><HideOnlineAccountScreens>true</HideOnlineAccountScreens>\
+     <HideWirelessSetupInOOBE>true</HideWirelessSetupInOOBE><ProtectYourPC>3</ProtectYourPC></OOBE>\
+     <UserAccounts><LocalAccounts><LocalAccount wcm:action=\"add\"><Name>{user}</Name><DisplayName>{user}</DisplayName><Group>Administrators</Group>{password}</LocalAccount></LocalAccounts></UserAccounts>\
+     <AutoLogon><Enabled>true</Enabled><Username>{user}</Username><LogonCount>1</LogonCount>{password}</AutoLogon>",
+    user=xml_escape(&user), password=password_xml,
+  );
+  if !first_logon_cmds.is_empty() {
+    shell_setup.push_str("<FirstLogonCommands>");
+    for (order, cmd) in first_logon_cmds.iter().enumerate() {
+      shell_setup.push_str(&format!(
+        "<SynchronousCommand wcm:action=\"add\"><Order>{}</Order><CommandLine>{}</CommandLine></SynchronousCommand>", order + 1, xml_escape(cmd)));
+    }
+    shell_setup.push_str("</FirstLogonCommands>");
+  }
+  let mut oobe_system = String::new();
+  oobe_system.push_str(&component("Microsoft-Windows-International-Core", &locales));
+  oobe_system.push_str(&component("Microsoft-Windows-Shell-Setup", &shell_setup));
+
+  format!(
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<unattend xmlns=\"urn:schemas-microsoft-com:unattend\">\n\
+     <settings pass=\"windowsPE\">\n{}</settings>\n<settings pass=\"specialize\">\n{}</settings>\n<settings pass=\"oobeSystem\">\n{}</settings>\n</unattend>\n",
+    windows_pe, specialize, oobe_system,
+  )
+}
+
+fn disk_configuration(uefi: bool) -> String {
+  let (create, modify) = if uefi {
+    (
+      "<CreatePartition wcm:action=\"add\"><Order>1</Order><Type>EFI</Type><Size>300</Size></CreatePartition>\
+       <CreatePartition wcm:action=\"add\"><Order>2</Order><Type>MSR</Type><Size>16</Size></CreatePartition>\
+       <CreatePartition wcm:action=\"add\"><Order>3</Order><Type>Primary</Type><Extend>true</Extend></CreatePartition>",
+      "<ModifyPartition wcm:action=\"add\"><Order>1</Order><PartitionID>1</PartitionID><Format>FAT32</Format><Label>System</Label></ModifyPartition>\
+       <ModifyPartition wcm:action=\"add\"><Order>2</Order><PartitionID>2</PartitionID></ModifyPartition>\
+       <ModifyPartition wcm:action=\"add\"><Order>3</Order><PartitionID>3</PartitionID><Format>NTFS</Format><Label>Windows</Label><Letter>C</Letter></ModifyPartition>",
+    )
+  }
+  else {
+    (
+      "<CreatePartition wcm:action=\"add\"><Order>1</Order><Type>Primary</Type><Size>100</Size></CreatePartition>\
+       <CreatePartition wcm:action=\"add\"><Order>2</Order><Type>Primary</Type><Extend>true</Extend></CreatePartition>",
+      "<ModifyPartition wcm:action=\"add\"><Order>1</Order><PartitionID>1</PartitionID><Format>NTFS</Format><Label>System Reserved</Label><Active>true</Active></ModifyPartition>\
+       <ModifyPartition wcm:action=\"add\"><Order>2</Order><PartitionID>2</PartitionID><Format>NTFS</Format><Label>Windows</Label><Letter>C</Letter></ModifyPartition>",
+    )
+  };
+  format!(
+    "<DiskConfiguration><Disk wcm:action=\"add\"><DiskID>0</DiskID><WillWipeDisk>true</WillWipeDisk><CreatePartitions>{}</CreatePartitions><ModifyPartitions>{}</ModifyPartitions></Disk></DiskConfiguration>",
+    create, modify,
+  )
+}
+
+fn component(name: &str, body: &str) -> String {
+  format!(
+    "<component name=\"{}\" processorArchitecture=\"amd64\" publicKeyToken=\"31bf3856ad364e35\" language=\"neutral\" versionScope=\"nonSxS\" \
+     xmlns:wcm=\"http://schemas.microsoft.com/WMIConfig/2002/State\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">{}</component>\n",
+    name, body,
+  )
+}
+
+// unattend.user, else the account part of rdp_uname, else "user"
+fn unattend_user(vm_config: &VMConfig, unattend: &UnattendBlock) -> String {
+  if !unattend.user.is_empty() {
+    return unattend.user.clone();
+  }
+  let rdp_uname = &vm_config.vm.rdp_uname;
+  let account = rdp_uname.rsplit('\\').next().unwrap_or_default();
+  let account = account.split('@').next().unwrap_or_default();
+  if account.is_empty() { "user".to_string() } else { account.to_string() }
+}
+
+// NetBIOS names are at most 15 letters, digits and dashes; * lets Setup pick one
+fn computer_name(vm_config: &VMConfig, unattend: &UnattendBlock) -> String {
+  let wanted = if unattend.computer_name.is_empty() { &vm_config.vm.name } else { &unattend.computer_name };
+  let name: String = wanted.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-').take(15).collect();
+  if name.is_empty() { "*".to_string() } else { name }
+}
+
+fn xml_escape(s: &str) -> String {
+  s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}